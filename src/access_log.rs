@@ -0,0 +1,92 @@
+
+use crate::deps;
+
+use deps::serde_json;
+
+use std::fs::OpenOptions;
+use std::io::{
+    Error,
+    LineWriter,
+    Write,
+};
+use std::path::Path;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+
+/// records waiting for the writer thread; more are dropped rather than
+/// holding up the requests that produce them
+const QUEUE_LEN: usize = 4096;
+
+/// JSON-lines sink with one record per request, written by a thread of its
+/// own so that requests never wait on the file or the terminal
+pub struct AccessLog {
+    queue: Option<SyncSender<serde_json::Value>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLog {
+    fn spawn(out: Box<dyn Write + Send>) -> Result<Self, Error> {
+        let (queue, records) = mpsc::sync_channel::<serde_json::Value>(QUEUE_LEN);
+        let writer = std::thread::Builder::new()
+            .name("access-log".to_owned())
+            .spawn(move || {
+                let mut out = out;
+                for record in records {
+                    let mut line = record.to_string();
+                    line.push('\n');
+                    if let Err(e) = out.write_all(line.as_bytes()) {
+                        deps::log::warn!("failed to write access log: {}", e);
+                    }
+                }
+                let _ = out.flush();
+            })?;
+        Ok(Self { queue: Some(queue), writer: Some(writer) })
+    }
+
+    pub fn stdout() -> Result<Self, Error> {
+        Self::spawn(Box::new(LineWriter::new(std::io::stdout())))
+    }
+
+    /// append to `path`, creating it if needed
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Self::spawn(Box::new(LineWriter::new(file)))
+    }
+
+    /// `stdout` or `-` for standard output, a file path otherwise
+    pub fn open_target(target: &str) -> Result<Self, Error> {
+        match target {
+            "stdout" | "-" => Self::stdout(),
+            path => Self::open(Path::new(path)),
+        }
+    }
+
+    /// queue `record` for the writer; `false` if the queue was full and the
+    /// record was dropped
+    pub fn write(&self, record: serde_json::Value) -> bool {
+        match self.queue.as_ref().map(|queue| queue.try_send(record)) {
+            Some(Ok(())) => true,
+            Some(Err(TrySendError::Full(_))) => false,
+            Some(Err(TrySendError::Disconnected(_))) | None => {
+                deps::log::warn!("access log writer is gone");
+                false
+            }
+        }
+    }
+}
+
+/// writes out whatever is still queued
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AccessLog")
+    }
+}
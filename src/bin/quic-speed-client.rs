@@ -0,0 +1,235 @@
+
+fn main() {
+    inner::main_inner();
+}
+
+#[cfg(not(feature = "build-binaries"))]
+mod inner {
+    pub(crate) fn main_inner() {
+        panic!("Binaries are disabled");
+    }
+}
+
+#[cfg(feature = "build-binaries")]
+mod inner {
+    #![allow(unused_imports)]
+
+    use std::time::Duration;
+
+    use quic_speed::deps::*;
+    use quic_speed::bin_deps::*;
+
+    use quic_speed::client::{LatencyMode, SpeedTestClient, SpeedTestResult, TestBudget, TransferResult};
+    use quic_speed::dns::DnsResolver;
+    use quic_speed::proto::{HttpVersion, Payload};
+
+    use clap::Parser;
+
+    #[derive(Parser, Debug, Clone)]
+    #[command(version, about, long_about = None)]
+    struct Args {
+        /// URL of the speed test server, e.g. https://speed.example.com
+        url: String,
+
+        /// Protocol to use: h1, h2 or h3 (default: h2 for https, h1 for http)
+        #[arg(short = 'p', long, conflicts_with = "compare")]
+        protocol: Option<HttpVersion>,
+
+        /// Run the test over HTTP/1.1, HTTP/2 and HTTP/3 in turn and print a comparison table
+        #[arg(long)]
+        compare: bool,
+
+        /// Time budget per direction in seconds
+        #[arg(short = 'd', long)]
+        duration: Option<f64>,
+
+        /// Number of parallel connections (HTTP/1.1) or streams (HTTP/2, HTTP/3)
+        #[arg(short = 'n', long, default_value = "1")]
+        streams: usize,
+
+        /// Byte budget per direction
+        #[arg(short = 'b', long)]
+        bytes: Option<u64>,
+
+        /// Bind to a specific device
+        #[arg(long)]
+        bind_device: Option<String>,
+
+        /// Download payload: zeros or random (default: server setting)
+        #[arg(long)]
+        payload: Option<Payload>,
+
+        /// Measure latency over one long-lived echo stream instead of separate ping requests
+        #[arg(long)]
+        echo: bool,
+
+        /// Skip TLS certificate verification
+        #[arg(short = 'k', long)]
+        insecure: bool,
+
+        /// Print results as JSON
+        #[arg(long)]
+        json: bool,
+    }
+
+    /// same scaling as `formatNumber` in the web page
+    fn format_number(n: f64) -> String {
+        if n > 1_000_000_000.0 {
+            format!("{:.2} G", n / 1_000_000_000.0)
+        } else if n > 1_000_000.0 {
+            format!("{:.2} M", n / 1_000_000.0)
+        } else if n > 1_000.0 {
+            format!("{:.2} k", n / 1_000.0)
+        } else {
+            format!("{} ", n)
+        }
+    }
+
+    fn format_ms(d: Option<Duration>) -> String {
+        match d {
+            Some(d) => format!("{:.2} ms", d.as_secs_f64() * 1000.0),
+            None => "n/a".to_owned(),
+        }
+    }
+
+    /// server-side speed of the last step; not meaningful once flows overlap
+    fn format_server_speed(result: &SpeedTestResult, step: Option<&TransferResult>) -> String {
+        if result.streams > 1 {
+            return String::new();
+        }
+        match step.and_then(|r| r.server_bits_per_second()) {
+            Some(bps) => format!(", {}bps at the server", format_number(bps as f64)),
+            None => String::new(),
+        }
+    }
+
+    fn print_result(url: &str, result: &SpeedTestResult) {
+        println!("Server:    {} ({})", url, result.peer_addr);
+        println!("Protocol:  {}", result.http_version);
+        println!("Connect:   {}", format_ms(Some(result.connect_time)));
+        match &result.session_id {
+            Some(session) => println!("Streams:   {} (session {})", result.streams, session),
+            None => println!("Streams:   {}", result.streams),
+        }
+        let stats = result.latency_stats();
+        println!("Latency:   {} (min {}, {} samples)", format_ms(result.latency_avg()), format_ms(result.latency_min()), result.latency.len());
+        println!("Jitter:    {} ({} stalls)", format_ms(stats.map(|s| s.jitter)), stats.map(|s| s.stalls).unwrap_or(0));
+        let failed = match (&result.download_loaded_latency, &result.upload_loaded_latency) {
+            (Some(_), None) => ", upload probe failed",
+            (None, Some(_)) => ", download probe failed",
+            _ => "",
+        };
+        match (result.loaded_latency(), result.loaded_latency_stats()) {
+            (Some(samples), loaded) => println!("Loaded:    {} (median {}, jitter {}, {} stalls, {} samples{})", format_ms(loaded.map(|s| s.avg)), format_ms(loaded.map(|s| s.median)), format_ms(loaded.map(|s| s.jitter)), loaded.map(|s| s.stalls).unwrap_or(0), samples.len(), failed),
+            (None, _) => println!("Loaded:    n/a (latency probe failed)"),
+        }
+        match result.responsiveness() {
+            Some(rpm) => println!("RPM:       {}", rpm),
+            None => println!("RPM:       n/a"),
+        }
+        println!("Download:  {}bps ({}B transferred{})", format_number(result.download_bits_per_second() as f64), format_number(result.download_bytes() as f64), format_server_speed(result, result.download.last()));
+        println!("Upload:    {}bps ({}B transferred{})", format_number(result.upload_bits_per_second() as f64), format_number(result.upload_bytes() as f64), format_server_speed(result, result.upload.last()));
+    }
+
+    fn format_bps(bps: u64) -> String {
+        format!("{}bps", format_number(bps as f64))
+    }
+
+    fn print_comparison(url: &str, results: &[(HttpVersion, Result<SpeedTestResult, std::io::Error>)]) {
+        println!("Server: {}", url);
+        println!();
+        println!("{:<10} {:>12} {:>12} {:>12} {:>12} {:>14} {:>14}", "Protocol", "Connect", "Latency", "Jitter", "Loaded", "Download", "Upload");
+        for (http_version, result) in results {
+            match result {
+                Ok(result) => {
+                    let stats = result.latency_stats();
+                    let loaded = result.loaded_latency_stats();
+                    println!(
+                        "{:<10} {:>12} {:>12} {:>12} {:>12} {:>14} {:>14}",
+                        http_version.to_string(),
+                        format_ms(Some(result.connect_time)),
+                        format_ms(stats.map(|s| s.median)),
+                        format_ms(stats.map(|s| s.jitter)),
+                        format_ms(loaded.map(|s| s.median)),
+                        format_bps(result.download_bits_per_second()),
+                        format_bps(result.upload_bits_per_second()),
+                    );
+                }
+                Err(e) => println!("{:<10} failed: {}", http_version.to_string(), e),
+            }
+        }
+    }
+
+    fn comparison_json(results: &[(HttpVersion, Result<SpeedTestResult, std::io::Error>)]) -> serde_json::Value {
+        serde_json::Value::Array(results.iter().map(|(http_version, result)| match result {
+            Ok(result) => result.to_json(),
+            Err(e) => serde_json::json!({
+                "http_version": http_version.to_string(),
+                "error": e.to_string(),
+            }),
+        }).collect())
+    }
+
+    pub(crate) fn main_inner() {
+        let args = Args::parse();
+
+        let protocol = args.protocol.unwrap_or(if args.url.starts_with("https:") {
+            HttpVersion::Http2
+        } else {
+            HttpVersion::Http1
+        });
+
+        let duration = match args.duration {
+            Some(secs) if !(secs > 0.0 && secs.is_finite()) => {
+                eprintln!("Invalid duration: {}", secs);
+                std::process::exit(2);
+            }
+            Some(secs) => Some(Duration::from_secs_f64(secs)),
+            None => None,
+        };
+        let budget = TestBudget {
+            duration,
+            bytes: args.bytes,
+        };
+
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let client = SpeedTestClient::new(DnsResolver::new_auto())
+            .with_bind_device(args.bind_device.as_deref().map(|s| s.as_bytes()))
+            .with_insecure(args.insecure)
+            .with_budget(budget)
+            .with_streams(args.streams)
+            .with_payload(args.payload)
+            .with_latency_mode(if args.echo { LatencyMode::Echo } else { LatencyMode::Ping });
+
+        if args.compare {
+            let results = rt.block_on(client.compare(&args.url));
+            if args.json {
+                println!("{}", comparison_json(&results));
+            } else {
+                print_comparison(&args.url, &results);
+            }
+            if results.iter().all(|(_, result)| result.is_err()) {
+                std::process::exit(1);
+            }
+            return;
+        }
+
+        let result = match rt.block_on(client.run(&args.url, protocol)) {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("Speed test failed: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        if args.json {
+            println!("{}", result.to_json());
+        } else {
+            print_result(&args.url, &result);
+        }
+    }
+}
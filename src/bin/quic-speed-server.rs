@@ -16,19 +16,20 @@ mod inner {
 
     use std::error;
     use std::path::PathBuf;
+    use std::time::Duration;
 
     use quic_speed::deps::*;
     use quic_speed::bin_deps::*;
     use quic_speed::config::*;
 
     use quic_speed::server;
+    use quic_speed::certs;
 
     use signal_hook::consts::signal::*;
     use signal_hook::iterator::Signals;
 
     use clap::Parser;
-    use syslog::{Facility, Formatter3164, BasicLogger};
-    use log::{SetLoggerError, LevelFilter, info, error, warn};
+    use log::{info, error, warn};
     use std::sync::Arc;
     use parking_lot::RwLock;
 
@@ -36,8 +37,13 @@ mod inner {
     #[command(version, about, long_about = None)]  
     struct Args {
         /// Path to the config file
-        #[arg(short = 'c', long)]
-        config: PathBuf,
+        #[arg(short = 'c', long, required_unless_present = "generate_self_signed")]
+        config: Option<PathBuf>,
+
+        /// Serve an ephemeral self-signed certificate for these host names
+        /// (comma separated) instead of tls_cert/tls_key; the config file becomes optional
+        #[arg(long, value_delimiter = ',')]
+        generate_self_signed: Option<Vec<String>>,
 
         /// Enable verbose logging
         #[arg(short = 'v', long)]
@@ -51,36 +57,30 @@ mod inner {
     pub(crate) fn main_inner() {
         let args = Args::parse();
 
-        let formatter = Formatter3164 {
-            facility: Facility::LOG_DAEMON,
-            hostname: None,
-            process: std::env::args().next().unwrap(),
-            pid: 0,
-        };
-
-        let logger = match syslog::unix(formatter) {
-            Err(e) => {
-                eprintln!("impossible to connect to syslog: {:?}", e);
-                return;
-            },
-            Ok(logger) => logger,
-        };
-
-        let level_filter = if args.verbose {
-            LevelFilter::Debug
+        let mut config = if let Some(config_path) = &args.config {
+            match Config::load(config_path) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Error loading config: {:?}", e);
+                    return;
+                }
+            }
         } else {
-            LevelFilter::Info
+            Config::default()
         };
 
-        if let Err(e)
-            = log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-                .map(|()| log::set_max_level(level_filter))
-        {
+        if let Err(e) = quic_speed::logging::init(&config.logging, args.verbose) {
             eprintln!("impossible to set logger: {:?}", e);
             return;
         }
 
-        let sigs = vec![SIGHUP];
+        if let Some(config_path) = &args.config {
+            info!("Starting quic-speed-server {} with config: {}", env!("CARGO_PKG_VERSION"), config_path.display());
+        } else {
+            info!("Starting quic-speed-server {} with default config", env!("CARGO_PKG_VERSION"));
+        }
+
+        let sigs = vec![SIGHUP, SIGTERM, SIGINT];
         let mut signals = if let Ok(signals) = Signals::new(&sigs) {
             signals
         } else {
@@ -89,15 +89,31 @@ mod inner {
             return;
         };
         
-        info!("Starting quic-speed-server {} with config: {}", env!("CARGO_PKG_VERSION"), args.config.display());
+        if let Some(hostnames) = &args.generate_self_signed {
+            let hostnames: Vec<&str> = hostnames.iter().map(|s| s.as_str()).collect();
+            match certs::generate_self_signed(&hostnames) {
+                Ok(self_signed) => {
+                    info!("Generated self-signed certificate for {}, SHA-256 fingerprint {}", hostnames.join(", "), self_signed.fingerprint());
+                    config.server.self_signed = Some(self_signed);
+                }
+                Err(e) => {
+                    eprintln!("Error generating self-signed certificate: {:?}", e);
+                    return;
+                }
+            }
+        }
 
-        let config = match Config::load(&args.config) {
-            Ok(config) => config,
+        match config.check_certificates() {
+            Ok(infos) => {
+                for (path, info) in infos {
+                    info!("Loaded certificate {}: {}", path.display(), info);
+                }
+            }
             Err(e) => {
-                eprintln!("Error loading config: {:?}", e);
+                eprintln!("Error checking certificates: {:?}", e);
                 return;
             }
-        };
+        }
 
         let tls_acceptor = match config.tls_acceptor() {
             Ok(acceptor) => acceptor,
@@ -107,49 +123,148 @@ mod inner {
             }
         };
         let tls_acceptor = Arc::new(RwLock::new(tls_acceptor));
-        info!("Config loaded");
 
-        let plain_http = if let Ok(server) = server::PlainHttpServer::new(80, args.bind_device.as_deref().map(|s| s.as_bytes())) {
-            server
-        } else {
-            eprintln!("Failed to initialize plain http server");
-            return;
+        let quic_config = match config.quic_server_config() {
+            Ok(quic_config) => quic_config,
+            Err(e) => {
+                eprintln!("Error loading keys: {:?}", e);
+                return;
+            }
         };
+        let quic_config = Arc::new(RwLock::new(quic_config));
+        info!("Config loaded");
 
-        let tls_http = if let Ok(server) = server::TlsHttpServer::new(tls_acceptor.clone(), 443, args.bind_device.as_deref().map(|s| s.as_bytes())) {
-            server
-        } else {
-            eprintln!("Failed to initialize TLS http server");
-            return;
-        };
+        let metrics = Arc::new(quic_speed::metrics::ServerMetrics::new());
+        let mut server_options = server::ServerOptions::default()
+            .with_default_payload(config.server.download_payload)
+            .with_metrics(metrics.clone(), config.server.public_metrics);
+        if let (Some(max_age), Some(addr)) = (config.server.alt_svc_max_age, config.server.quic_listen.first()) {
+            server_options = server_options.with_h3_alt_svc(addr.port(), max_age);
+        }
+        if let Some(max) = config.server.max_upload_bytes {
+            server_options = server_options.with_max_upload_bytes(max);
+        }
+        if let Some(max) = config.server.max_test_duration {
+            server_options = server_options.with_max_test_duration(Duration::from_secs(max));
+        }
+        if let Some(timeout) = config.server.idle_timeout {
+            server_options = server_options.with_idle_timeout(Duration::from_secs(timeout));
+        }
+        let limits = &config.server.client_limits;
+        server_options = server_options.with_client_limits(quic_speed::limits::ClientLimits {
+            max_concurrent_tests: limits.max_concurrent_tests,
+            max_bytes_per_minute: limits.max_bytes_per_minute,
+            max_requests_per_second: limits.max_requests_per_second,
+        });
+        let limits = &config.server.global_limits;
+        server_options = server_options.with_global_limits(quic_speed::limits::GlobalLimits {
+            max_connections_per_listener: limits.max_connections_per_listener,
+            max_concurrent_tests: limits.max_concurrent_tests,
+            max_egress_bits_per_second: limits.max_egress_bits_per_second,
+            max_ingress_bits_per_second: limits.max_ingress_bits_per_second,
+        });
+        if let Some(target) = &config.server.access_log {
+            match quic_speed::access_log::AccessLog::open_target(target) {
+                Ok(access_log) => server_options = server_options.with_access_log(Arc::new(access_log)),
+                Err(e) => {
+                    eprintln!("Failed to open access log {}: {:?}", target, e);
+                    return;
+                }
+            }
+        }
+        let server_options = Arc::new(server_options);
+
+        let bind_device = args.bind_device.as_deref().map(|s| s.as_bytes());
+
+        let mut plain_http = Vec::new();
+        for addr in &config.server.plain_listen {
+            match server::PlainHttpServer::new_with_addr(*addr, bind_device) {
+                Ok(server) => plain_http.push(server),
+                Err(e) => {
+                    eprintln!("Failed to initialize plain http server on {}: {:?}", addr, e);
+                    return;
+                }
+            }
+        }
+
+        let mut tls_http = Vec::new();
+        for addr in &config.server.tls_listen {
+            match server::TlsHttpServer::new_with_addr(tls_acceptor.clone(), *addr, bind_device) {
+                Ok(server) => tls_http.push(server),
+                Err(e) => {
+                    eprintln!("Failed to initialize TLS http server on {}: {:?}", addr, e);
+                    return;
+                }
+            }
+        }
+
+        let mut http3 = Vec::new();
+        for addr in &config.server.quic_listen {
+            match server::Http3Server::new_with_addr(quic_config.clone(), *addr, bind_device) {
+                Ok(server) => http3.push(server),
+                Err(e) => {
+                    eprintln!("Failed to initialize HTTP/3 server on {}: {:?}", addr, e);
+                    return;
+                }
+            }
+        }
+
+        let mut metrics_servers = Vec::new();
+        for addr in &config.server.metrics_listen {
+            match server::MetricsServer::new_with_addr(metrics.clone(), *addr, bind_device) {
+                Ok(server) => metrics_servers.push(server),
+                Err(e) => {
+                    eprintln!("Failed to initialize metrics server on {}: {:?}", addr, e);
+                    return;
+                }
+            }
+        }
+
+        // without a config file there is nothing on disk to reload from
+        let reloader = args.config.as_ref().map(|config_path| {
+            quic_speed::reload::TlsReloader::new(config_path, tls_acceptor.clone(), quic_config.clone())
+                .with_self_signed(config.server.self_signed.clone())
+        });
+        if let Some(reloader) = &reloader {
+            if config.server.cert_reload_interval > 0 {
+                reloader.clone().watch(config.clone(), Duration::from_secs(config.server.cert_reload_interval));
+            }
+        }
 
-        let tls_acceptor_clone = tls_acceptor.clone();
+        let shutdown = quic_speed::shutdown::Shutdown::new();
+        let drain_timeout = Duration::from_secs(config.server.drain_timeout);
+
+        let signal_shutdown = shutdown.clone();
         std::thread::spawn(move || {
             for sig in signals.forever() {
                 info!("Received signal: {:?}", sig);
                 match sig {
                     SIGHUP => {
+                        let reloader = if let Some(reloader) = &reloader {
+                            reloader
+                        } else {
+                            warn!("No config file to reload");
+                            continue;
+                        };
                         info!("Reloading config");
-                        match Config::load(&args.config) {
-                            Ok(config) => {
-                                let tls_acceptor = match config.tls_acceptor() {
-                                    Ok(acceptor) => acceptor,
-                                    Err(e) => {
-                                        eprintln!("Error loading keys: {:?}", e);
-                                        continue;
-                                    }
-                                };
-                                {
-                                    let mut w = tls_acceptor_clone.write();
-                                    *w = tls_acceptor;
-                                }
+                        match reloader.reload() {
+                            Ok(_) => {
                                 info!("Config reloaded");
                             },
                             Err(e) => {
+                                error!("Error reloading config: {:?}", e);
                                 eprintln!("Error reloading config: {:?}", e);
                             }
                         }
                     },
+                    SIGTERM | SIGINT => {
+                        if signal_shutdown.is_triggered() {
+                            warn!("Second termination signal, exiting without draining");
+                            std::process::exit(1);
+                        }
+                        info!("Shutting down, draining connections for up to {:?}", drain_timeout);
+                        signal_shutdown.trigger(drain_timeout);
+                    },
                     _ => {
                         warn!("Unhandled signal: {:?}", sig);
                     }
@@ -157,11 +272,25 @@ mod inner {
             }
         });
 
-        plain_http.start();
-        tls_http.start();
+        let mut threads = Vec::new();
+        for server in plain_http {
+            threads.push(server.with_options(server_options.clone()).with_shutdown(shutdown.clone()).start());
+        }
+        for server in tls_http {
+            threads.push(server.with_options(server_options.clone()).with_shutdown(shutdown.clone()).start());
+        }
+        for server in http3 {
+            threads.push(server.with_options(server_options.clone()).with_shutdown(shutdown.clone()).start());
+        }
+        for server in metrics_servers {
+            threads.push(server.with_shutdown(shutdown.clone()).start());
+        }
 
-        loop {
-            std::thread::park();
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("Server thread panicked");
+            }
         }
+        info!("Shut down");
     }
 }
@@ -1,8 +1,30 @@
 
 use crate::deps;
+use crate::time::civil_from_unix;
 
 use deps::x509_parser;
+use deps::tokio_rustls::rustls;
+use deps::ring;
 use x509_parser::pem::Pem;
+use x509_parser::time::ASN1Time;
+use x509_parser::extensions::GeneralName;
+use x509_parser::public_key::PublicKey;
+use x509_parser::oid_registry::OID_SIG_ED25519;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, EcdsaKeyPair, KeyPair};
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{
+    Error,
+    ErrorKind,
+};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub fn parse_certs(certs: &[u8]) -> Vec<Vec<u8>> {
     let pem_iter = Pem::iter_from_buffer(certs);
@@ -34,3 +56,398 @@ pub fn parse_cert(cert: &[u8]) -> Option<Vec<u8>> {
     }
     None
 }
+
+/// the parts of a certificate an operator cares about
+#[derive(Debug, Clone)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub subject_alt_names: Vec<String>,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    /// e.g. `RSA 2048` or `EC 256`
+    pub key_type: String,
+}
+
+fn asn1_to_system_time(time: ASN1Time) -> SystemTime {
+    let secs = time.timestamp();
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+fn format_system_time(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    ASN1Time::from_timestamp(secs)
+        .ok()
+        .and_then(|t| t.to_rfc2822().ok())
+        .unwrap_or_else(|| format!("@{}", secs))
+}
+
+impl CertificateInfo {
+    pub fn from_der(der: &[u8]) -> Result<Self, Error> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid certificate: {}", e)))?;
+
+        let mut subject_alt_names = Vec::new();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) => subject_alt_names.push(name.to_string()),
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => subject_alt_names.push(IpAddr::from(<[u8; 4]>::try_from(*ip).unwrap()).to_string()),
+                        16 => subject_alt_names.push(IpAddr::from(<[u8; 16]>::try_from(*ip).unwrap()).to_string()),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        let spki = cert.public_key();
+        let key_type = if spki.algorithm.algorithm == OID_SIG_ED25519 {
+            "Ed25519".to_owned()
+        } else {
+            match spki.parsed() {
+                Ok(PublicKey::RSA(rsa)) => format!("RSA {}", rsa.key_size()),
+                Ok(PublicKey::EC(ec)) => format!("EC {}", ec.key_size()),
+                _ => spki.algorithm.algorithm.to_id_string(),
+            }
+        };
+
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            subject_alt_names,
+            not_before: asn1_to_system_time(cert.validity().not_before),
+            not_after: asn1_to_system_time(cert.validity().not_after),
+            key_type,
+        })
+    }
+
+    /// leaf certificate of a PEM chain
+    pub fn from_pem(pem: &[u8]) -> Result<Self, Error> {
+        let der = parse_cert(pem).ok_or_else(|| Error::new(ErrorKind::NotFound, "no certificate found"))?;
+        Self::from_der(&der)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() > self.not_after
+    }
+
+    pub fn expires_within(&self, window: Duration) -> bool {
+        SystemTime::now() + window > self.not_after
+    }
+
+    /// time left until `not_after`, zero when already expired
+    pub fn remaining(&self) -> Duration {
+        self.not_after.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+    }
+
+    pub fn not_after_string(&self) -> String {
+        format_system_time(self.not_after)
+    }
+}
+
+impl Display for CertificateInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} [{}] ({}, issued by {}, valid {} - {})",
+            self.subject,
+            self.subject_alt_names.join(", "),
+            self.key_type,
+            self.issuer,
+            format_system_time(self.not_before),
+            format_system_time(self.not_after),
+        )
+    }
+}
+
+/// picks a certificate by SNI: exact name first, then `*.` wildcard of the parent
+/// domain, falling back to the default certificate
+#[derive(Debug)]
+pub struct SniCertResolver {
+    default: Arc<CertifiedKey>,
+    names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniCertResolver {
+    pub fn new(default: Arc<CertifiedKey>) -> Self {
+        Self { default, names: HashMap::new() }
+    }
+
+    /// `name` may be an exact host name or a wildcard like `*.example.com`
+    pub fn add(&mut self, name: &str, key: Arc<CertifiedKey>) {
+        self.names.insert(name.trim_end_matches('.').to_ascii_lowercase(), key);
+    }
+
+    fn lookup(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        let server_name = server_name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(key) = self.names.get(&server_name) {
+            return Some(key.clone());
+        }
+        let (_, parent) = server_name.split_once('.')?;
+        self.names.get(&format!("*.{}", parent)).cloned()
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello.server_name().and_then(|name| self.lookup(name));
+        Some(key.unwrap_or_else(|| self.default.clone()))
+    }
+}
+
+/// validity of certificates made by `generate_self_signed`
+pub const SELF_SIGNED_VALIDITY: Duration = Duration::from_secs(30 * 86400);
+
+/// an ephemeral ECDSA P-256 certificate and its PKCS#8 key, both DER
+#[derive(Debug, Clone)]
+pub struct SelfSignedCertificate {
+    pub cert_der: Vec<u8>,
+    pub key_der: Vec<u8>,
+}
+
+impl SelfSignedCertificate {
+    pub fn certified_key(&self) -> Result<Arc<CertifiedKey>, Error> {
+        let cert = CertificateDer::from(self.cert_der.clone());
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key_der.clone()));
+        let provider = rustls::crypto::ring::default_provider();
+        let certified_key = CertifiedKey::from_der(vec![cert], key, &provider)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Arc::new(certified_key))
+    }
+
+    /// SHA-256 of the certificate, as browsers show it
+    pub fn fingerprint(&self) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &self.cert_der);
+        digest.as_ref().iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")
+    }
+}
+
+/// minimal DER writer, just enough for a certificate
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn der_seq(items: &[Vec<u8>]) -> Vec<u8> {
+    der(0x30, &items.concat())
+}
+
+/// `oid` is the already encoded body, e.g. `[0x55, 0x04, 0x03]` for 2.5.4.3
+fn der_oid(oid: &[u8]) -> Vec<u8> {
+    der(0x06, oid)
+}
+
+/// UTCTime until 2049, GeneralizedTime after (RFC 5280 section 4.1.2.5)
+fn der_time(time: SystemTime) -> Vec<u8> {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day, hour, minute, second) = civil_from_unix(secs);
+    let hms = format!("{:02}{:02}{:02}{:02}{:02}Z", month, day, hour, minute, second);
+    if year < 2050 {
+        der(0x17, format!("{:02}{}", year % 100, hms).as_bytes())
+    } else {
+        der(0x18, format!("{:04}{}", year, hms).as_bytes())
+    }
+}
+
+/// make a certificate for `hostnames` (DNS names or IP addresses) signed by its own
+/// freshly generated key; the first name becomes the subject CN
+pub fn generate_self_signed(hostnames: &[&str]) -> Result<SelfSignedCertificate, Error> {
+    let first = hostnames.first().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no host names given"))?;
+    let rng = SystemRandom::new();
+    let alg = &signature::ECDSA_P256_SHA256_ASN1_SIGNING;
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng)
+        .map_err(|_| Error::new(ErrorKind::Other, "failed to generate key"))?;
+    let key_pair = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng)
+        .map_err(|_| Error::new(ErrorKind::Other, "failed to load generated key"))?;
+
+    let mut serial = [0u8; 16];
+    rng.fill(&mut serial).map_err(|_| Error::new(ErrorKind::Other, "failed to generate serial"))?;
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+
+    // ecdsa-with-SHA256
+    let signature_algorithm = der_seq(&[der_oid(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02])]);
+    // CN
+    let name = der_seq(&[der(0x31, &der_seq(&[
+        der_oid(&[0x55, 0x04, 0x03]),
+        der(0x0c, first.as_bytes()),
+    ]))]);
+    let now = SystemTime::now();
+    let validity = der_seq(&[
+        der_time(now - Duration::from_secs(3600)),
+        der_time(now + SELF_SIGNED_VALIDITY),
+    ]);
+    // id-ecPublicKey, prime256v1
+    let spki = der_seq(&[
+        der_seq(&[
+            der_oid(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01]),
+            der_oid(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07]),
+        ]),
+        der(0x03, &[&[0u8][..], key_pair.public_key().as_ref()].concat()),
+    ]);
+
+    let mut general_names = Vec::new();
+    for host in hostnames {
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => general_names.push(der(0x87, &ip.octets())),
+            Ok(IpAddr::V6(ip)) => general_names.push(der(0x87, &ip.octets())),
+            Err(_) => general_names.push(der(0x82, host.as_bytes())),
+        }
+    }
+    let extensions = der_seq(&[
+        // subjectAltName
+        der_seq(&[der_oid(&[0x55, 0x1d, 0x11]), der(0x04, &der_seq(&general_names))]),
+        // basicConstraints, critical, CA:FALSE
+        der_seq(&[der_oid(&[0x55, 0x1d, 0x13]), der(0x01, &[0xff]), der(0x04, &der_seq(&[]))]),
+        // extKeyUsage serverAuth
+        der_seq(&[der_oid(&[0x55, 0x1d, 0x25]), der(0x04, &der_seq(&[der_oid(&[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01])]))]),
+    ]);
+
+    let tbs = der_seq(&[
+        der(0xa0, &der(0x02, &[2])),
+        der(0x02, &serial),
+        signature_algorithm.clone(),
+        name.clone(),
+        validity,
+        name,
+        spki,
+        der(0xa3, &extensions),
+    ]);
+    let signature = key_pair.sign(&rng, &tbs)
+        .map_err(|_| Error::new(ErrorKind::Other, "failed to sign certificate"))?;
+    let cert_der = der_seq(&[
+        tbs,
+        signature_algorithm,
+        der(0x03, &[&[0u8][..], signature.as_ref()].concat()),
+    ]);
+
+    Ok(SelfSignedCertificate {
+        cert_der,
+        key_der: pkcs8.as_ref().to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ring::signature::UnparsedPublicKey;
+
+    #[test]
+    fn der_length_encoding() {
+        assert_eq!(der(0x04, &[0; 3]), [0x04, 0x03, 0, 0, 0]);
+        assert_eq!(&der(0x04, &[0; 0x7f])[..2], [0x04, 0x7f]);
+        assert_eq!(&der(0x04, &[0; 0x80])[..3], [0x04, 0x81, 0x80]);
+        assert_eq!(&der(0x04, &[0; 300])[..4], [0x04, 0x82, 0x01, 0x2c]);
+    }
+
+    #[test]
+    fn der_time_switches_to_generalized_time_after_2049() {
+        let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(der_time(at(0)), der(0x17, b"700101000000Z"));
+        // leap day
+        assert_eq!(der_time(at(951_827_696)), der(0x17, b"000229123456Z"));
+        assert_eq!(der_time(at(2_524_607_999)), der(0x17, b"491231235959Z"));
+        assert_eq!(der_time(at(2_524_608_000)), der(0x18, b"20500101000000Z"));
+        assert_eq!(der_time(at(4_102_444_800)), der(0x18, b"21000101000000Z"));
+    }
+
+    #[test]
+    fn self_signed_round_trip() {
+        let long_names: Vec<String> = (0..40).map(|i| format!("host-{}.speed.example.com", i)).collect();
+        let mut hostnames = vec!["speed.example.com", "127.0.0.1", "::1"];
+        hostnames.extend(long_names.iter().map(String::as_str));
+
+        let before = SystemTime::now();
+        let generated = generate_self_signed(&hostnames).unwrap();
+        let (rest, cert) = x509_parser::parse_x509_certificate(&generated.cert_der).unwrap();
+        assert!(rest.is_empty());
+
+        // over 255 bytes of names, so the SAN extension needs a two-byte length
+        assert!(long_names.iter().map(String::len).sum::<usize>() > 255);
+        assert_eq!(cert.subject().to_string(), "CN=speed.example.com");
+        assert_eq!(cert.issuer().to_string(), "CN=speed.example.com");
+
+        let info = CertificateInfo::from_der(&generated.cert_der).unwrap();
+        assert_eq!(info.subject_alt_names, hostnames);
+        assert_eq!(info.key_type, "EC 256");
+
+        let not_before = asn1_to_system_time(cert.validity().not_before);
+        let not_after = asn1_to_system_time(cert.validity().not_after);
+        assert!(not_before <= before && before - Duration::from_secs(3601) <= not_before);
+        let expected_not_after = before + SELF_SIGNED_VALIDITY;
+        assert!(not_after + Duration::from_secs(1) >= expected_not_after);
+        assert!(not_after <= expected_not_after + Duration::from_secs(60));
+
+        let public_key = UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, cert.public_key().subject_public_key.data.as_ref());
+        public_key.verify(cert.tbs_certificate.as_ref(), cert.signature_value.data.as_ref()).unwrap();
+
+        // rustls checks that the key matches the certificate
+        generated.certified_key().unwrap();
+    }
+
+    /// in-memory handshake of a client trusting the certificate against a server presenting it
+    fn handshake(generated: &SelfSignedCertificate, server_name: &str) -> Result<(), rustls::Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(generated.cert_der.clone())).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SniCertResolver::new(generated.certified_key().unwrap())));
+
+        let name = rustls::pki_types::ServerName::try_from(server_name.to_owned()).unwrap();
+        let mut client = rustls::ClientConnection::new(Arc::new(client_config), name)?;
+        let mut server = rustls::ServerConnection::new(Arc::new(server_config))?;
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut buf).unwrap();
+            }
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets()?;
+
+            let mut buf = Vec::new();
+            while server.wants_write() {
+                server.write_tls(&mut buf).unwrap();
+            }
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn self_signed_handshake() {
+        let generated = generate_self_signed(&["speed.example.com", "127.0.0.1", "::1", "other.example.com"]).unwrap();
+        for name in ["speed.example.com", "other.example.com", "127.0.0.1", "::1"] {
+            handshake(&generated, name).unwrap();
+        }
+        assert!(handshake(&generated, "unlisted.example.com").is_err());
+    }
+
+    #[test]
+    fn no_hostnames() {
+        assert_eq!(generate_self_signed(&[]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,1261 @@
+
+use crate::deps;
+use crate::dns::{
+    DnsResolver,
+    HostAddrs,
+};
+use crate::inet::AddressFamily;
+use crate::proto::{HttpVersion, Payload, TransferSample};
+use crate::tcp;
+use crate::udp;
+
+use deps::tokio;
+use deps::hyper;
+use deps::hyper_util;
+use deps::http_body_util;
+use deps::futures;
+use deps::quinn;
+use deps::h3;
+use deps::h3_quinn;
+use deps::serde_json;
+use deps::tokio_rustls;
+use deps::rustls_platform_verifier;
+
+use hyper::body::{Buf, Bytes, Frame};
+use hyper::header::HeaderMap;
+use hyper::client::conn::{http1, http2};
+use hyper::{Method, Request, Response, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, StreamBody};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use rustls_platform_verifier::ConfigVerifierExt;
+
+use std::convert::Infallible;
+use std::io::{
+    Error,
+    ErrorKind,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// same ladder as `HttpSpeedTest` in `static/index.html`
+pub const CHUNK_SIZES: [usize; 6] = [1 << 20, 4 << 20, 16 << 20, 64 << 20, 256 << 20, 1 << 30];
+
+/// the ladder stops after the first step that takes longer than this
+pub const STEP_TIME_LIMIT: Duration = Duration::from_secs(4);
+
+/// number of round trips sampled before the transfers
+pub const LATENCY_SAMPLES: usize = 10;
+
+/// pause between echo probes
+pub const ECHO_INTERVAL: Duration = Duration::from_millis(50);
+
+/// pause between probes on the latency connection while a transfer is running
+pub const LOADED_PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// a round trip this much slower than the median is counted as a stall,
+/// roughly what a lost packet costs once it has to be retransmitted
+pub const STALL_THRESHOLD: Duration = Duration::from_millis(200);
+
+static ZEROS: [u8; 65536] = [0u8; 65536];
+
+type ClientBody = BoxBody<Bytes, Infallible>;
+
+fn other_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::new(ErrorKind::Other, e.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Download => "download",
+            Direction::Upload => "upload",
+        }
+    }
+}
+
+/// limits for one direction of the test
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TestBudget {
+    /// keep transferring until this much time is spent, instead of stopping
+    /// at the first step longer than `STEP_TIME_LIMIT`
+    pub duration: Option<Duration>,
+    /// steps are trimmed so that the total never exceeds this
+    pub bytes: Option<u64>,
+}
+
+/// one request of the ladder
+#[derive(Debug, Clone)]
+pub struct TransferResult {
+    pub direction: Direction,
+    /// index of the parallel flow that made the request
+    pub stream: usize,
+    pub transferred_bytes: u64,
+    pub elapsed: Duration,
+    /// as reported by the server in `X-Http-Version`
+    pub http_version: HttpVersion,
+    /// first to last byte as seen by the server, excluding request setup
+    pub server_elapsed: Option<Duration>,
+    /// per-interval byte counts as seen by the server, when it reports them
+    pub server_samples: Vec<TransferSample>,
+}
+
+impl TransferResult {
+    pub fn bits_per_second(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return 0;
+        }
+        (self.transferred_bytes as f64 * 8.0 / secs) as u64
+    }
+
+    /// throughput over the server's own timing, when it reports one
+    pub fn server_bits_per_second(&self) -> Option<u64> {
+        let secs = self.server_elapsed?.as_secs_f64();
+        if secs <= 0.0 {
+            return None;
+        }
+        Some((self.transferred_bytes as f64 * 8.0 / secs) as u64)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "direction": self.direction.as_str(),
+            "stream": self.stream,
+            "transferred_bytes": self.transferred_bytes,
+            "elapsed_ms": self.elapsed.as_secs_f64() * 1000.0,
+            "bits_per_second": self.bits_per_second(),
+            "http_version": self.http_version.to_string(),
+            "server_elapsed_ms": self.server_elapsed.map(|d| d.as_secs_f64() * 1000.0),
+            "server_bits_per_second": self.server_bits_per_second(),
+            "server_samples": self.server_samples.iter().map(|s| s.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn samples_from_json(v: &serde_json::Value) -> Vec<TransferSample> {
+    v.as_array()
+        .map(|a| a.iter().filter_map(TransferSample::from_json).collect())
+        .unwrap_or_default()
+}
+
+/// response of a completed request
+struct Exchange {
+    response: Response<()>,
+    /// body length
+    len: u64,
+    /// the body itself, when asked to keep it
+    body: Vec<u8>,
+    trailers: Option<HeaderMap>,
+}
+
+/// how idle round trips are measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatencyMode {
+    /// one `GET /ping` request per sample
+    #[default]
+    Ping,
+    /// probes written to a single long-lived `POST /echo` stream
+    Echo,
+}
+
+/// summary of a set of round trip samples
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub median: Duration,
+    pub avg: Duration,
+    /// mean difference between consecutive samples
+    pub jitter: Duration,
+    /// samples exceeding the median by more than `STALL_THRESHOLD`
+    pub stalls: usize,
+}
+
+impl LatencyStats {
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let median = sorted[sorted.len() / 2];
+        let avg = samples.iter().sum::<Duration>() / samples.len() as u32;
+        let jitter = if samples.len() > 1 {
+            samples.windows(2)
+                .map(|w| if w[1] > w[0] { w[1] - w[0] } else { w[0] - w[1] })
+                .sum::<Duration>() / (samples.len() - 1) as u32
+        } else {
+            Duration::ZERO
+        };
+        let stalls = samples.iter().filter(|d| **d > median + STALL_THRESHOLD).count();
+        Some(Self {
+            min: sorted[0],
+            median,
+            avg,
+            jitter,
+            stalls,
+        })
+    }
+
+    /// round trips per minute at the median, the responsiveness score of RPM
+    pub fn round_trips_per_minute(&self) -> u32 {
+        if self.median.is_zero() {
+            return 0;
+        }
+        (60.0 / self.median.as_secs_f64()) as u32
+    }
+}
+
+fn latency_json(samples: &[Duration]) -> serde_json::Value {
+    let stats = LatencyStats::from_samples(samples);
+    serde_json::json!({
+        "min_ms": stats.map(|s| s.min.as_secs_f64() * 1000.0),
+        "avg_ms": stats.map(|s| s.avg.as_secs_f64() * 1000.0),
+        "median_ms": stats.map(|s| s.median.as_secs_f64() * 1000.0),
+        "jitter_ms": stats.map(|s| s.jitter.as_secs_f64() * 1000.0),
+        "stalls": stats.map(|s| s.stalls),
+        "samples_ms": samples.iter().map(|d| d.as_secs_f64() * 1000.0).collect::<Vec<_>>(),
+    })
+}
+
+/// upload and download ladders, run by one or more parallel flows
+#[derive(Debug, Clone)]
+pub struct SpeedTestResult {
+    pub http_version: HttpVersion,
+    pub peer_addr: SocketAddr,
+    /// TCP+TLS or QUIC handshake time of the first connection
+    pub connect_time: Duration,
+    /// id the server tagged this test's requests with, when it hands them out
+    pub session_id: Option<String>,
+    /// number of parallel flows: connections for HTTP/1.1, streams otherwise
+    pub streams: usize,
+    /// idle round trips measured before the transfers
+    pub latency: Vec<Duration>,
+    /// round trips on a second connection while the downloads were running;
+    /// `None` when the probe failed
+    pub download_loaded_latency: Option<Vec<Duration>>,
+    /// the same while the uploads were running
+    pub upload_loaded_latency: Option<Vec<Duration>>,
+    /// steps of every flow
+    pub download: Vec<TransferResult>,
+    pub upload: Vec<TransferResult>,
+    /// wall time of each direction, from the first request to the last flow finishing
+    pub download_elapsed: Duration,
+    pub upload_elapsed: Duration,
+}
+
+/// with one flow, speed of the last (largest) step as the page reports it;
+/// with several, everything moved over the wall time of the direction
+fn aggregate_bits_per_second(steps: &[TransferResult], streams: usize, elapsed: Duration) -> u64 {
+    if streams <= 1 {
+        return steps.last().map(|r| r.bits_per_second()).unwrap_or(0);
+    }
+    let secs = elapsed.as_secs_f64();
+    if secs <= 0.0 {
+        return 0;
+    }
+    let bytes: u64 = steps.iter().map(|r| r.transferred_bytes).sum();
+    (bytes as f64 * 8.0 / secs) as u64
+}
+
+impl SpeedTestResult {
+    pub fn download_bits_per_second(&self) -> u64 {
+        aggregate_bits_per_second(&self.download, self.streams, self.download_elapsed)
+    }
+
+    pub fn upload_bits_per_second(&self) -> u64 {
+        aggregate_bits_per_second(&self.upload, self.streams, self.upload_elapsed)
+    }
+
+    pub fn latency_min(&self) -> Option<Duration> {
+        self.latency.iter().min().copied()
+    }
+
+    pub fn latency_avg(&self) -> Option<Duration> {
+        if self.latency.is_empty() {
+            return None;
+        }
+        Some(self.latency.iter().sum::<Duration>() / self.latency.len() as u32)
+    }
+
+    pub fn latency_stats(&self) -> Option<LatencyStats> {
+        LatencyStats::from_samples(&self.latency)
+    }
+
+    /// loaded round trips of both directions, or of the one whose probe
+    /// succeeded; `None` when both failed
+    pub fn loaded_latency(&self) -> Option<Vec<Duration>> {
+        match (&self.download_loaded_latency, &self.upload_loaded_latency) {
+            (None, None) => None,
+            (download, upload) => Some(download.iter().chain(upload).flatten().copied().collect()),
+        }
+    }
+
+    pub fn loaded_latency_stats(&self) -> Option<LatencyStats> {
+        self.loaded_latency().as_deref().and_then(LatencyStats::from_samples)
+    }
+
+    /// round trips per minute under load
+    pub fn responsiveness(&self) -> Option<u32> {
+        self.loaded_latency_stats().map(|s| s.round_trips_per_minute())
+    }
+
+    pub fn download_bytes(&self) -> u64 {
+        self.download.iter().map(|r| r.transferred_bytes).sum()
+    }
+
+    pub fn upload_bytes(&self) -> u64 {
+        self.upload.iter().map(|r| r.transferred_bytes).sum()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "http_version": self.http_version.to_string(),
+            "peer_addr": self.peer_addr.to_string(),
+            "address_family": AddressFamily::of(&self.peer_addr).to_string(),
+            "connect_time_ms": self.connect_time.as_secs_f64() * 1000.0,
+            "session_id": self.session_id,
+            "streams": self.streams,
+            "latency": latency_json(&self.latency),
+            "loaded_latency": self.loaded_latency().as_deref().map(latency_json),
+            "responsiveness_rpm": self.responsiveness(),
+            "download": {
+                "bits_per_second": self.download_bits_per_second(),
+                "transferred_bytes": self.download_bytes(),
+                "elapsed_ms": self.download_elapsed.as_secs_f64() * 1000.0,
+                "loaded_latency": self.download_loaded_latency.as_deref().map(latency_json),
+                "steps": self.download.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+            },
+            "upload": {
+                "bits_per_second": self.upload_bits_per_second(),
+                "transferred_bytes": self.upload_bytes(),
+                "elapsed_ms": self.upload_elapsed.as_secs_f64() * 1000.0,
+                "loaded_latency": self.upload_loaded_latency.as_deref().map(latency_json),
+                "steps": self.upload.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+            },
+        })
+    }
+}
+
+/// scheme, host and port of the server under test
+#[derive(Debug, Clone)]
+struct Target {
+    tls: bool,
+    host: String,
+    port: u16,
+}
+
+impl Target {
+    fn parse(url: &str) -> Result<Self, Error> {
+        let uri: Uri = url.parse().map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid url {}: {}", url, e)))?;
+        let tls = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("unsupported url scheme: {}", url))),
+        };
+        let host = uri.host()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("no host in url: {}", url)))?
+            .to_owned();
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        Ok(Self { tls, host, port })
+    }
+
+    /// host without the brackets of an IPv6 literal
+    fn hostname(&self) -> &str {
+        self.host.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    fn authority(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    fn uri(&self, path: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}{}", scheme, self.authority(), path)
+    }
+
+    fn server_name(&self) -> Result<ServerName<'static>, Error> {
+        ServerName::try_from(self.hostname().to_owned())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+}
+
+/// accepts any certificate; for self-signed lab servers only
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<rustls::crypto::CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+enum Sender {
+    Http1(http1::SendRequest<ClientBody>),
+    Http2(http2::SendRequest<ClientBody>),
+    Http3 {
+        send_request: h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+        // keeps the socket alive as long as the connection
+        _endpoint: quinn::Endpoint,
+    },
+}
+
+impl Sender {
+    /// another handle opening streams on the same connection; HTTP/1.1 has
+    /// no streams to share
+    fn new_stream(&self) -> Option<Sender> {
+        match self {
+            Sender::Http1(_) => None,
+            Sender::Http2(sender) => Some(Sender::Http2(sender.clone())),
+            Sender::Http3 { send_request, _endpoint } => Some(Sender::Http3 {
+                send_request: send_request.clone(),
+                _endpoint: _endpoint.clone(),
+            }),
+        }
+    }
+}
+
+/// one `POST /echo` exchange in flight
+enum EchoStream {
+    Hyper {
+        probes: tokio::sync::mpsc::Sender<Bytes>,
+        replies: hyper::body::Incoming,
+    },
+    Http3(Box<h3::client::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>>),
+}
+
+impl EchoStream {
+    async fn send(&mut self, data: Bytes) -> Result<(), Error> {
+        match self {
+            EchoStream::Hyper { probes, .. } => probes.send(data).await.map_err(|_| other_error("echo stream closed")),
+            EchoStream::Http3(stream) => stream.send_data(data).await.map_err(other_error),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Option<Bytes>, Error> {
+        match self {
+            EchoStream::Hyper { replies, .. } => {
+                while let Some(frame) = replies.frame().await {
+                    if let Ok(data) = frame.map_err(other_error)?.into_data() {
+                        return Ok(Some(data));
+                    }
+                }
+                Ok(None)
+            }
+            EchoStream::Http3(stream) => match stream.recv_data().await.map_err(other_error)? {
+                Some(mut data) => Ok(Some(data.copy_to_bytes(data.remaining()))),
+                None => Ok(None),
+            },
+        }
+    }
+
+    async fn finish(self) -> Result<(), Error> {
+        match self {
+            EchoStream::Hyper { probes, mut replies } => {
+                drop(probes);
+                while let Some(frame) = replies.frame().await {
+                    frame.map_err(other_error)?;
+                }
+                Ok(())
+            }
+            EchoStream::Http3(mut stream) => {
+                stream.finish().await.map_err(other_error)?;
+                while stream.recv_data().await.map_err(other_error)?.is_some() {}
+                Ok(())
+            }
+        }
+    }
+}
+
+/// an established connection to the server under test
+pub struct SpeedTestConnection {
+    target: Target,
+    sender: Sender,
+    http_version: HttpVersion,
+    peer_addr: SocketAddr,
+    connect_time: Duration,
+    /// requested download content; the server default when unset
+    payload: Option<Payload>,
+    /// sent as `X-Test-Session` on every request
+    session: Option<String>,
+    /// index among the parallel flows of a test
+    stream: usize,
+}
+
+impl SpeedTestConnection {
+    pub fn http_version(&self) -> HttpVersion {
+        self.http_version
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn connect_time(&self) -> Duration {
+        self.connect_time
+    }
+
+    /// `payload=` parameter for download requests
+    fn payload_query(&self, sep: char) -> String {
+        match self.payload {
+            Some(payload) => format!("{}payload={}", sep, payload),
+            None => String::new(),
+        }
+    }
+
+    /// flow `stream` of the same test over another stream of this connection,
+    /// or `None` over HTTP/1.1 where each flow needs its own connection
+    pub fn new_stream(&self, stream: usize) -> Option<SpeedTestConnection> {
+        Some(SpeedTestConnection {
+            target: self.target.clone(),
+            sender: self.sender.new_stream()?,
+            http_version: self.http_version,
+            peer_addr: self.peer_addr,
+            connect_time: self.connect_time,
+            payload: self.payload,
+            session: self.session.clone(),
+            stream,
+        })
+    }
+
+    /// ask the server for a test-session id and tag further requests with it;
+    /// `None` if the server does not hand them out
+    pub async fn open_session(&mut self) -> Result<Option<String>, Error> {
+        let req = self.build_request(Method::POST, "/session")
+            .body(Empty::<Bytes>::new().boxed())
+            .map_err(other_error)?;
+        let exchange = self.exchange(req, true).await?;
+        if !exchange.response.status().is_success() {
+            return Ok(None);
+        }
+        let json: serde_json::Value = serde_json::from_slice(&exchange.body).map_err(other_error)?;
+        self.session = json["session_id"].as_str().map(|s| s.to_owned());
+        Ok(self.session.clone())
+    }
+
+    fn build_request(&self, method: Method, path: &str) -> hyper::http::request::Builder {
+        let builder = match self.sender {
+            Sender::Http1(_) => Request::builder()
+                .method(method)
+                .uri(path)
+                .header(hyper::header::HOST, self.target.authority()),
+            _ => Request::builder()
+                .method(method)
+                .uri(self.target.uri(path)),
+        };
+        match &self.session {
+            Some(session) => builder.header("x-test-session", session.as_str()),
+            None => builder,
+        }
+    }
+
+    fn reported_version(&self, res: &Response<()>) -> HttpVersion {
+        res.headers().get("x-http-version")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(self.http_version)
+    }
+
+    /// send a request and read the whole response body, keeping the body
+    /// itself only when `keep_body` is set
+    async fn exchange(&mut self, req: Request<ClientBody>, keep_body: bool) -> Result<Exchange, Error> {
+        let res = match &mut self.sender {
+            Sender::Http1(sender) => {
+                sender.ready().await.map_err(other_error)?;
+                sender.send_request(req).await.map_err(other_error)?
+            }
+            Sender::Http2(sender) => {
+                sender.ready().await.map_err(other_error)?;
+                sender.send_request(req).await.map_err(other_error)?
+            }
+            Sender::Http3 { send_request, .. } => {
+                let (parts, mut body) = req.into_parts();
+                let mut stream = send_request.send_request(Request::from_parts(parts, ())).await.map_err(other_error)?;
+                while let Some(frame) = body.frame().await {
+                    let frame = match frame {
+                        Ok(frame) => frame,
+                        Err(e) => match e {},
+                    };
+                    if let Ok(data) = frame.into_data() {
+                        stream.send_data(data).await.map_err(other_error)?;
+                    }
+                }
+                stream.finish().await.map_err(other_error)?;
+
+                let response = stream.recv_response().await.map_err(other_error)?;
+                let mut len: u64 = 0;
+                let mut kept = Vec::new();
+                while let Some(mut data) = stream.recv_data().await.map_err(other_error)? {
+                    len += data.remaining() as u64;
+                    if keep_body {
+                        kept.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+                    } else {
+                        data.advance(data.remaining());
+                    }
+                }
+                let trailers = stream.recv_trailers().await.map_err(other_error)?;
+                return Ok(Exchange { response, len, body: kept, trailers });
+            }
+        };
+
+        let (parts, mut body) = res.into_parts();
+        let mut len: u64 = 0;
+        let mut kept = Vec::new();
+        let mut trailers = None;
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(other_error)?;
+            match frame.into_data() {
+                Ok(data) => {
+                    len += data.len() as u64;
+                    if keep_body {
+                        kept.extend_from_slice(&data);
+                    }
+                }
+                Err(frame) => {
+                    if let Ok(t) = frame.into_trailers() {
+                        trailers = Some(t);
+                    }
+                }
+            }
+        }
+        Ok(Exchange {
+            response: Response::from_parts(parts, ()),
+            len,
+            body: kept,
+            trailers,
+        })
+    }
+
+    /// `GET /download/{len}`
+    pub async fn download(&mut self, len: usize) -> Result<TransferResult, Error> {
+        let req = self.build_request(Method::GET, &format!("/download/{}{}", len, self.payload_query('?')))
+            .body(Empty::<Bytes>::new().boxed())
+            .map_err(other_error)?;
+
+        let start = Instant::now();
+        let exchange = self.exchange(req, false).await?;
+        let elapsed = start.elapsed();
+        let (res, transferred) = (exchange.response, exchange.len);
+
+        if !res.status().is_success() {
+            return Err(other_error(format!("download failed: {}", res.status())));
+        }
+        if transferred != len as u64 {
+            return Err(other_error("Downloaded bytes does not match"));
+        }
+
+        Ok(TransferResult {
+            direction: Direction::Download,
+            stream: self.stream,
+            transferred_bytes: transferred,
+            elapsed,
+            http_version: self.reported_version(&res),
+            server_elapsed: None,
+            server_samples: Vec::new(),
+        })
+    }
+
+    /// `GET /download?duration=`, streaming for a fixed time instead of a fixed size
+    pub async fn download_for(&mut self, duration: Duration) -> Result<TransferResult, Error> {
+        let req = self.build_request(Method::GET, &format!("/download?duration={}ms{}", duration.as_millis(), self.payload_query('&')))
+            .header(hyper::header::TE, "trailers")
+            .body(Empty::<Bytes>::new().boxed())
+            .map_err(other_error)?;
+
+        let start = Instant::now();
+        let exchange = self.exchange(req, false).await?;
+        let elapsed = start.elapsed();
+        let res = exchange.response;
+
+        if !res.status().is_success() {
+            return Err(other_error(format!("download failed: {}", res.status())));
+        }
+        let trailers = exchange.trailers.unwrap_or_default();
+        let trailer = |name: &str| trailers.get(name).and_then(|v| v.to_str().ok());
+        if let Some(sent) = trailer("x-transferred-bytes").and_then(|v| v.parse::<u64>().ok()) {
+            if sent != exchange.len {
+                return Err(other_error("Downloaded bytes does not match"));
+            }
+        }
+        let server_elapsed = trailer("x-elapsed-ms")
+            .and_then(|v| v.parse::<f64>().ok())
+            .map(|ms| Duration::from_secs_f64(ms / 1000.0));
+        let server_samples = trailer("x-samples")
+            .and_then(|v| serde_json::from_str::<serde_json::Value>(v).ok())
+            .map(|v| samples_from_json(&v))
+            .unwrap_or_default();
+
+        Ok(TransferResult {
+            direction: Direction::Download,
+            stream: self.stream,
+            transferred_bytes: exchange.len,
+            elapsed,
+            http_version: self.reported_version(&res),
+            server_elapsed,
+            server_samples,
+        })
+    }
+
+    /// `POST /upload` with `len` zero bytes
+    pub async fn upload(&mut self, len: usize) -> Result<TransferResult, Error> {
+        let mut remaining = len;
+        let chunks = futures::stream::iter(std::iter::from_fn(move || {
+            let chunk = std::cmp::min(remaining, ZEROS.len());
+            remaining -= chunk;
+            if chunk > 0 {
+                Some(Ok(Frame::data(Bytes::from_static(&ZEROS[..chunk]))))
+            } else {
+                None
+            }
+        }));
+        let req = self.build_request(Method::POST, "/upload")
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header(hyper::header::CONTENT_LENGTH, len)
+            .body(BoxBody::new(StreamBody::new(chunks)))
+            .map_err(other_error)?;
+
+        let start = Instant::now();
+        let exchange = self.exchange(req, true).await?;
+        let elapsed = start.elapsed();
+        let (res, body) = (exchange.response, exchange.body);
+
+        if !res.status().is_success() {
+            return Err(other_error(format!("upload failed: {}", res.status())));
+        }
+        let json: serde_json::Value = serde_json::from_slice(&body).map_err(other_error)?;
+        if json["uploaded_bytes"].as_u64() != Some(len as u64) {
+            return Err(other_error("Uploaded bytes does not match"));
+        }
+
+        Ok(TransferResult {
+            direction: Direction::Upload,
+            stream: self.stream,
+            transferred_bytes: len as u64,
+            elapsed,
+            http_version: self.reported_version(&res),
+            server_elapsed: json["elapsed_ms"].as_f64().map(|ms| Duration::from_secs_f64(ms / 1000.0)),
+            server_samples: samples_from_json(&json["samples"]),
+        })
+    }
+
+    /// round trip of a `GET /ping` request
+    pub async fn ping(&mut self) -> Result<Duration, Error> {
+        let req = self.build_request(Method::GET, "/ping")
+            .body(Empty::<Bytes>::new().boxed())
+            .map_err(other_error)?;
+
+        let start = Instant::now();
+        let res = self.exchange(req, false).await?.response;
+        let elapsed = start.elapsed();
+
+        if !res.status().is_success() {
+            return Err(other_error(format!("ping failed: {}", res.status())));
+        }
+        Ok(elapsed)
+    }
+
+    async fn open_echo(&mut self) -> Result<EchoStream, Error> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Bytes>(1);
+        let probes = futures::stream::poll_fn(move |cx| {
+            rx.poll_recv(cx).map(|data| data.map(|data| Ok::<_, Infallible>(Frame::data(data))))
+        });
+        let req = self.build_request(Method::POST, "/echo")
+            .header(hyper::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(BoxBody::new(StreamBody::new(probes)))
+            .map_err(other_error)?;
+
+        let res = match &mut self.sender {
+            Sender::Http1(sender) => {
+                sender.ready().await.map_err(other_error)?;
+                sender.send_request(req).await.map_err(other_error)?
+            }
+            Sender::Http2(sender) => {
+                sender.ready().await.map_err(other_error)?;
+                sender.send_request(req).await.map_err(other_error)?
+            }
+            Sender::Http3 { send_request, .. } => {
+                // probes are written straight to the stream instead of the channel
+                let (parts, _) = req.into_parts();
+                let mut stream = send_request.send_request(Request::from_parts(parts, ())).await.map_err(other_error)?;
+                let res = stream.recv_response().await.map_err(other_error)?;
+                if !res.status().is_success() {
+                    return Err(other_error(format!("echo failed: {}", res.status())));
+                }
+                return Ok(EchoStream::Http3(Box::new(stream)));
+            }
+        };
+        if !res.status().is_success() {
+            return Err(other_error(format!("echo failed: {}", res.status())));
+        }
+        Ok(EchoStream::Hyper { probes: tx, replies: res.into_body() })
+    }
+
+    /// round trips of `count` probes sent `interval` apart over one `POST /echo` stream
+    pub async fn echo(&mut self, count: usize, interval: Duration) -> Result<Vec<Duration>, Error> {
+        let mut stream = self.open_echo().await?;
+        let mut samples = Vec::with_capacity(count);
+        let mut buf: Vec<u8> = Vec::new();
+        for seq in 0..count {
+            if seq > 0 {
+                tokio::time::sleep(interval).await;
+            }
+            let mut probe = serde_json::to_vec(&serde_json::json!({ "seq": seq })).map_err(other_error)?;
+            probe.push(b'\n');
+
+            let start = Instant::now();
+            stream.send(Bytes::from(probe)).await?;
+            let line = loop {
+                if let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                    break buf.drain(..=pos).collect::<Vec<u8>>();
+                }
+                match stream.recv().await? {
+                    Some(data) => buf.extend_from_slice(&data),
+                    None => return Err(other_error("echo stream ended early")),
+                }
+            };
+            samples.push(start.elapsed());
+
+            let reply: serde_json::Value = serde_json::from_slice(&line).map_err(other_error)?;
+            if reply["seq"].as_u64() != Some(seq as u64) {
+                return Err(other_error("echo reply out of sequence"));
+            }
+        }
+        stream.finish().await?;
+        Ok(samples)
+    }
+
+    /// ping every `LOADED_PROBE_INTERVAL` until `load` completes; a probe in flight
+    /// is allowed to finish so the connection stays usable
+    pub async fn ping_during<F: std::future::Future>(&mut self, load: F) -> (F::Output, Result<Vec<Duration>, Error>) {
+        let done = std::cell::Cell::new(false);
+        let load = async {
+            let output = load.await;
+            done.set(true);
+            output
+        };
+        let probes = async {
+            let mut samples = Vec::new();
+            while !done.get() {
+                samples.push(self.ping().await?);
+                tokio::time::sleep(LOADED_PROBE_INTERVAL).await;
+            }
+            Ok(samples)
+        };
+        tokio::join!(load, probes)
+    }
+
+    /// walk `CHUNK_SIZES` until one step exceeds `STEP_TIME_LIMIT` or the budget is spent
+    /// downloads with only a time budget take a single timed request instead
+    pub async fn run_ladder(&mut self, direction: Direction, budget: TestBudget) -> Result<Vec<TransferResult>, Error> {
+        if let (Direction::Download, Some(duration), None) = (direction, budget.duration, budget.bytes) {
+            return Ok(vec![self.download_for(duration).await?]);
+        }
+
+        let largest = CHUNK_SIZES[CHUNK_SIZES.len() - 1];
+        // with a time budget the largest step repeats until the time is up
+        let repeats = if budget.duration.is_some() { usize::MAX } else { 0 };
+        let sizes = CHUNK_SIZES.into_iter().chain(std::iter::repeat(largest).take(repeats));
+
+        let start = Instant::now();
+        let mut total: u64 = 0;
+        let mut results: Vec<TransferResult> = Vec::new();
+        for chunk_size in sizes {
+            let mut chunk_size = chunk_size as u64;
+            if let Some(max_bytes) = budget.bytes {
+                if total >= max_bytes {
+                    break;
+                }
+                chunk_size = chunk_size.min(max_bytes - total);
+            }
+            if let Some(max_duration) = budget.duration {
+                let spent = start.elapsed();
+                if spent >= max_duration {
+                    break;
+                }
+                // shrink the step to what the last measured speed can move in the remaining time
+                if let Some(last) = results.last() {
+                    let fit = (last.bits_per_second() / 8) as f64 * (max_duration - spent).as_secs_f64();
+                    chunk_size = chunk_size.min((fit as u64).max(CHUNK_SIZES[0] as u64));
+                }
+            }
+
+            let result = match direction {
+                Direction::Download => self.download(chunk_size as usize).await?,
+                Direction::Upload => self.upload(chunk_size as usize).await?,
+            };
+            total += result.transferred_bytes;
+            let done = budget.duration.is_none() && result.elapsed > STEP_TIME_LIMIT;
+            results.push(result);
+            if done {
+                break;
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// native counterpart of the `HttpSpeedTest` class in the web page
+#[derive(Debug, Clone)]
+pub struct SpeedTestClient {
+    resolver: DnsResolver,
+    bind_device: Option<Vec<u8>>,
+    verify_certificates: bool,
+    budget: TestBudget,
+    latency_mode: LatencyMode,
+    payload: Option<Payload>,
+    streams: usize,
+}
+
+impl SpeedTestClient {
+    pub fn new(resolver: DnsResolver) -> Self {
+        Self {
+            resolver,
+            bind_device: None,
+            verify_certificates: true,
+            budget: TestBudget::default(),
+            latency_mode: LatencyMode::default(),
+            payload: None,
+            streams: 1,
+        }
+    }
+
+    pub fn with_bind_device(mut self, device: Option<&[u8]>) -> Self {
+        self.bind_device = device.map(|d| d.to_vec());
+        self
+    }
+
+    /// skip certificate verification, for servers with self-signed certificates
+    pub fn with_insecure(mut self, insecure: bool) -> Self {
+        self.verify_certificates = !insecure;
+        self
+    }
+
+    pub fn with_budget(mut self, budget: TestBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// ask for a specific download payload instead of the server default
+    pub fn with_payload(mut self, payload: Option<Payload>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// run each direction over `streams` parallel connections (HTTP/1.1) or
+    /// streams of one connection (HTTP/2, HTTP/3); a byte budget is split among them
+    pub fn with_streams(mut self, streams: usize) -> Self {
+        self.streams = streams.max(1);
+        self
+    }
+
+    pub fn with_latency_mode(mut self, latency_mode: LatencyMode) -> Self {
+        self.latency_mode = latency_mode;
+        self
+    }
+
+    fn tls_config(&self, http_version: HttpVersion) -> Result<rustls::ClientConfig, Error> {
+        let mut config = if self.verify_certificates {
+            rustls::ClientConfig::with_platform_verifier().map_err(other_error)?
+        } else {
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+                .with_no_client_auth()
+        };
+        config.alpn_protocols = vec![http_version.alpn().to_vec()];
+        Ok(config)
+    }
+
+    async fn resolve(&self, target: &Target) -> Result<HostAddrs, Error> {
+        if let Ok(ip) = target.hostname().parse::<IpAddr>() {
+            return Ok(HostAddrs::from_ip(ip));
+        }
+        self.resolver.resolve_host(&target.authority()).await
+    }
+
+    /// connect to `url`, speaking exactly `http_version`
+    pub async fn connect(&self, url: &str, http_version: HttpVersion) -> Result<SpeedTestConnection, Error> {
+        let target = Target::parse(url)?;
+        if !target.tls && http_version != HttpVersion::Http1 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} requires an https url", http_version)));
+        }
+
+        let addrs = self.resolve(&target).await?;
+        let start = Instant::now();
+        let (sender, peer_addr) = match http_version {
+            HttpVersion::Http3 => {
+                // no racing over QUIC yet; take the address a browser would try first
+                let peer_addr = *addrs.interleaved(target.port).first()
+                    .ok_or_else(|| Error::new(ErrorKind::AddrNotAvailable, "no addresses found"))?;
+                (self.connect_h3(&target, peer_addr).await?, peer_addr)
+            }
+            _ => {
+                let (stream, _) = tcp::connect_happy_eyeballs(&addrs, target.port, self.bind_device.as_deref()).await?;
+                let peer_addr = stream.peer_addr()?;
+                let sender = if target.tls {
+                    let connector = TlsConnector::from(Arc::new(self.tls_config(http_version)?));
+                    let stream = connector.connect(target.server_name()?, stream).await?;
+                    let negotiated = stream.get_ref().1.alpn_protocol().map(|p| p.to_vec());
+                    if negotiated.as_deref() != Some(http_version.alpn()) {
+                        return Err(other_error(format!("server did not negotiate {}", http_version)));
+                    }
+                    handshake(stream, http_version).await?
+                } else {
+                    handshake(stream, http_version).await?
+                };
+                (sender, peer_addr)
+            }
+        };
+        let connect_time = start.elapsed();
+
+        Ok(SpeedTestConnection {
+            target,
+            sender,
+            http_version,
+            peer_addr,
+            connect_time,
+            payload: self.payload,
+            session: None,
+            stream: 0,
+        })
+    }
+
+    async fn connect_h3(&self, target: &Target, peer_addr: SocketAddr) -> Result<Sender, Error> {
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(self.tls_config(HttpVersion::Http3)?)
+            .map_err(other_error)?;
+        let client_config = quinn::ClientConfig::new(Arc::new(crypto));
+
+        let socket = udp::bind_std_socket(0, self.bind_device.as_deref())?;
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+        let conn = endpoint.connect_with(client_config, peer_addr, target.hostname())
+            .map_err(other_error)?
+            .await
+            .map_err(other_error)?;
+
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(conn)).await.map_err(other_error)?;
+        tokio::task::spawn(async move {
+            let err = driver.wait_idle().await;
+            if !err.is_h3_no_error() {
+                log::debug!("http3 client connection closed: {err:#}");
+            }
+        });
+
+        Ok(Sender::Http3 { send_request, _endpoint: endpoint })
+    }
+
+    /// idle latency probes, then download ladder followed by upload ladder like
+    /// the web page, with loaded latency probed alongside the transfers
+    pub async fn run(&self, url: &str, http_version: HttpVersion) -> Result<SpeedTestResult, Error> {
+        let mut conn = self.connect(url, http_version).await?;
+        let session_id = conn.open_session().await?;
+        let latency = match self.latency_mode {
+            LatencyMode::Ping => {
+                let mut latency = Vec::with_capacity(LATENCY_SAMPLES);
+                for _ in 0..LATENCY_SAMPLES {
+                    latency.push(conn.ping().await?);
+                }
+                latency
+            }
+            LatencyMode::Echo => conn.echo(LATENCY_SAMPLES, ECHO_INTERVAL).await?,
+        };
+
+        let mut flows = vec![conn];
+        for stream in 1..self.streams {
+            let flow = match flows[0].new_stream(stream) {
+                Some(flow) => flow,
+                None => {
+                    let mut flow = self.connect(url, http_version).await?;
+                    flow.session = session_id.clone();
+                    flow.stream = stream;
+                    flow
+                }
+            };
+            flows.push(flow);
+        }
+        let budget = TestBudget {
+            duration: self.budget.duration,
+            bytes: self.budget.bytes.map(|bytes| bytes / self.streams as u64),
+        };
+
+        // probes use their own connection, so they queue behind the transfer
+        // only in the network and not in the stream scheduler
+        let mut probe = match self.connect(url, http_version).await {
+            Ok(probe) => Some(probe),
+            Err(e) => {
+                log::warn!("loaded latency unavailable: failed to connect probe: {}", e);
+                None
+            }
+        };
+        let start = Instant::now();
+        let (download, download_loaded_latency) = probe_during(probe.as_mut(), run_flows(&mut flows, Direction::Download, budget)).await;
+        let download_elapsed = start.elapsed();
+        let start = Instant::now();
+        let (upload, upload_loaded_latency) = probe_during(probe.as_mut(), run_flows(&mut flows, Direction::Upload, budget)).await;
+        let upload_elapsed = start.elapsed();
+        let download = download?;
+        let upload = upload?;
+
+        let conn = &flows[0];
+        Ok(SpeedTestResult {
+            http_version: conn.http_version(),
+            peer_addr: conn.peer_addr(),
+            connect_time: conn.connect_time(),
+            session_id,
+            streams: flows.len(),
+            latency,
+            download_loaded_latency,
+            upload_loaded_latency,
+            download,
+            upload,
+            download_elapsed,
+            upload_elapsed,
+        })
+    }
+
+    /// `run` once per protocol in `HttpVersion::ALL` against the same server,
+    /// one after another so the runs do not compete for the link
+    pub async fn compare(&self, url: &str) -> Vec<(HttpVersion, Result<SpeedTestResult, Error>)> {
+        let mut results = Vec::with_capacity(HttpVersion::ALL.len());
+        for http_version in HttpVersion::ALL {
+            results.push((http_version, self.run(url, http_version).await));
+        }
+        results
+    }
+}
+
+/// `load` with loaded latency probed alongside on `probe`; a failed probe
+/// only costs the samples, not the transfer
+async fn probe_during<F: std::future::Future>(probe: Option<&mut SpeedTestConnection>, load: F) -> (F::Output, Option<Vec<Duration>>) {
+    let probe = match probe {
+        Some(probe) => probe,
+        None => return (load.await, None),
+    };
+    match probe.ping_during(load).await {
+        (output, Ok(samples)) => (output, Some(samples)),
+        (output, Err(e)) => {
+            log::warn!("loaded latency unavailable: {}", e);
+            (output, None)
+        }
+    }
+}
+
+/// run the ladder on every flow at once, collecting the steps of all of them
+async fn run_flows(flows: &mut [SpeedTestConnection], direction: Direction, budget: TestBudget) -> Result<Vec<TransferResult>, Error> {
+    let results = futures::future::try_join_all(flows.iter_mut().map(|flow| flow.run_ladder(direction, budget))).await?;
+    Ok(results.into_iter().flatten().collect())
+}
+
+async fn handshake<I>(io: I, http_version: HttpVersion) -> Result<Sender, Error>
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let io = TokioIo::new(io);
+    match http_version {
+        HttpVersion::Http1 => {
+            let (sender, conn) = http1::handshake(io).await.map_err(other_error)?;
+            tokio::task::spawn(async move {
+                if let Err(e) = conn.await {
+                    log::debug!("http1 client connection error: {:?}", e);
+                }
+            });
+            Ok(Sender::Http1(sender))
+        }
+        HttpVersion::Http2 => {
+            let (sender, conn) = http2::handshake(TokioExecutor::new(), io).await.map_err(other_error)?;
+            tokio::task::spawn(async move {
+                if let Err(e) = conn.await {
+                    log::debug!("http2 client connection error: {:?}", e);
+                }
+            });
+            Ok(Sender::Http2(sender))
+        }
+        HttpVersion::Http3 => Err(Error::new(ErrorKind::InvalidInput, "HTTP/3 does not run over TCP")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(download: Option<Vec<Duration>>, upload: Option<Vec<Duration>>) -> SpeedTestResult {
+        SpeedTestResult {
+            http_version: HttpVersion::Http2,
+            peer_addr: "192.0.2.1:443".parse().unwrap(),
+            connect_time: Duration::ZERO,
+            session_id: None,
+            streams: 1,
+            latency: Vec::new(),
+            download_loaded_latency: download,
+            upload_loaded_latency: upload,
+            download: Vec::new(),
+            upload: Vec::new(),
+            download_elapsed: Duration::ZERO,
+            upload_elapsed: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn failed_probe_keeps_other_direction() {
+        let ms = Duration::from_millis;
+        let both = result(Some(vec![ms(10), ms(20)]), Some(vec![ms(30)]));
+        assert_eq!(both.loaded_latency(), Some(vec![ms(10), ms(20), ms(30)]));
+
+        let download_only = result(Some(vec![ms(10), ms(20)]), None);
+        assert_eq!(download_only.loaded_latency(), Some(vec![ms(10), ms(20)]));
+        assert!(download_only.responsiveness().is_some());
+        let json = download_only.to_json();
+        assert_eq!(json["download"]["loaded_latency"]["samples_ms"], serde_json::json!([10.0, 20.0]));
+        assert!(json["upload"]["loaded_latency"].is_null());
+
+        let neither = result(None, None);
+        assert_eq!(neither.loaded_latency(), None);
+        assert!(neither.to_json()["loaded_latency"].is_null());
+    }
+}
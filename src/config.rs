@@ -1,32 +1,296 @@
 
 use crate::deps;
+use crate::inet;
+use crate::proto::Payload;
+use crate::certs::{
+    CertificateInfo,
+    SelfSignedCertificate,
+    SniCertResolver,
+};
 
+use std::collections::BTreeMap;
 use std::path::{
     PathBuf,
     Path,
 };
 use std::str::FromStr;
+use std::net::SocketAddr;
 use std::io::{
     Error,
     ErrorKind,
 };
 
 use deps::toml;
-use deps::serde::Deserialize;
+use deps::log::LevelFilter;
+use deps::serde::{Deserialize, Deserializer};
 use deps::tokio_rustls::TlsAcceptor;
 use deps::rustls_pemfile;
 use deps::tokio_rustls::rustls;
+use rustls::sign::CertifiedKey;
+use deps::quinn;
+use quinn::crypto::rustls::QuicServerConfig;
 use std::sync::Arc;
+use std::time::Duration;
 
+/// an additional certificate, selected by SNI
 #[derive(Debug, Clone, Deserialize)]
-pub struct ServerConfig {
+pub struct CertificateConfig {
+    /// host names served with this certificate; `*.example.com` wildcards are allowed
+    pub server_names: Vec<String>,
     pub tls_cert: PathBuf,
     pub tls_key: PathBuf,
 }
 
+/// per-client limits; clients are IPv4 addresses or IPv6 /64 prefixes
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClientLimitsConfig {
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_concurrent_tests: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_bytes_per_minute: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_requests_per_second: Option<u32>,
+}
+
+/// server-wide limits across all listeners
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GlobalLimitsConfig {
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_connections_per_listener: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_concurrent_tests: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_egress_bits_per_second: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_ingress_bits_per_second: Option<u64>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    /// default certificate, used when no entry of `certificates` matches
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+
+    /// generated at startup; takes the place of `tls_cert`/`tls_key`
+    #[serde(skip)]
+    pub self_signed: Option<SelfSignedCertificate>,
+
+    #[serde(default)]
+    pub certificates: Vec<CertificateConfig>,
+
+    /// `ma=` of the `Alt-Svc: h3` header; HTTP/3 is not advertised when unset
+    #[serde(default)]
+    pub alt_svc_max_age: Option<u32>,
+
+    /// plain HTTP listen addresses; empty to disable
+    #[serde(default = "default_plain_listen")]
+    pub plain_listen: Vec<SocketAddr>,
+
+    /// HTTPS listen addresses; empty to disable
+    #[serde(default = "default_tls_listen")]
+    pub tls_listen: Vec<SocketAddr>,
+
+    /// HTTP/3 (UDP) listen addresses; empty to disable
+    #[serde(default = "default_tls_listen")]
+    pub quic_listen: Vec<SocketAddr>,
+
+    /// seconds between checks of the certificate files for changes; 0 disables
+    #[serde(default = "default_cert_reload_interval")]
+    pub cert_reload_interval: u64,
+
+    /// warn when a certificate expires within this many days
+    #[serde(default = "default_cert_expiry_warning_days")]
+    pub cert_expiry_warning_days: u64,
+
+    /// download content when the request has no `payload=` parameter
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub download_payload: Payload,
+
+    /// admin listen addresses serving Prometheus `/metrics`; empty to disable
+    #[serde(default)]
+    pub metrics_listen: Vec<SocketAddr>,
+
+    /// also serve `/metrics` on the public test listeners
+    #[serde(default)]
+    pub public_metrics: bool,
+
+    /// JSON-lines access log: `stdout`, or a file to append to; omit to disable
+    #[serde(default)]
+    pub access_log: Option<String>,
+
+    /// seconds open connections get to finish after SIGTERM/SIGINT before they are cut
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+
+    /// unlimited unless set
+    #[serde(default)]
+    pub client_limits: ClientLimitsConfig,
+
+    /// unlimited unless set
+    #[serde(default)]
+    pub global_limits: GlobalLimitsConfig,
+
+    /// largest accepted upload body, in bytes
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_upload_bytes: Option<u64>,
+
+    /// longest upload, in seconds; timed downloads asking for more end at it
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub max_test_duration: Option<u64>,
+
+    /// seconds without traffic after which TCP, TLS and QUIC connections are closed
+    #[serde(default, deserialize_with = "deserialize_nonzero")]
+    pub idle_timeout: Option<u64>,
+}
+
+/// deserialize a string through the type's `FromStr`
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(deps::serde::de::Error::custom)
+}
+
+/// deserialize an optional limit, refusing 0; such settings are omitted
+/// rather than zeroed to switch them off
+fn deserialize_nonzero<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    match Option::<T>::deserialize(deserializer)? {
+        Some(value) if value == T::default() => Err(deps::serde::de::Error::custom("must be greater than 0; omit it for no limit")),
+        value => Ok(value),
+    }
+}
+
+/// deserialize a table of module path to log level
+fn deserialize_level_map<'de, D>(deserializer: D) -> Result<BTreeMap<String, LevelFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map = BTreeMap::<String, String>::deserialize(deserializer)?;
+    map.into_iter()
+        .map(|(module, level)| level.parse().map(|level| (module, level)).map_err(deps::serde::de::Error::custom))
+        .collect()
+}
+
+fn default_drain_timeout() -> u64 {
+    10
+}
+
+fn default_cert_reload_interval() -> u64 {
+    60
+}
+
+fn default_cert_expiry_warning_days() -> u64 {
+    14
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tls_cert: None,
+            tls_key: None,
+            self_signed: None,
+            certificates: Vec::new(),
+            alt_svc_max_age: None,
+            plain_listen: default_plain_listen(),
+            tls_listen: default_tls_listen(),
+            quic_listen: default_tls_listen(),
+            cert_reload_interval: default_cert_reload_interval(),
+            cert_expiry_warning_days: default_cert_expiry_warning_days(),
+            download_payload: Payload::default(),
+            metrics_listen: Vec::new(),
+            public_metrics: false,
+            access_log: None,
+            drain_timeout: default_drain_timeout(),
+            client_limits: ClientLimitsConfig::default(),
+            global_limits: GlobalLimitsConfig::default(),
+            max_upload_bytes: None,
+            max_test_duration: None,
+            idle_timeout: None,
+        }
+    }
+}
+
+fn default_plain_listen() -> Vec<SocketAddr> {
+    vec![inet::socket_addr_unspecified(80)]
+}
+
+fn default_tls_listen() -> Vec<SocketAddr> {
+    vec![inet::socket_addr_unspecified(443)]
+}
+
+/// where server logs go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogBackend {
+    /// the local syslog daemon, falling back to stderr when `/dev/log` is missing
+    #[default]
+    Syslog,
+    Stderr,
+    File,
+}
+
+/// line format of the stderr and file backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub backend: LogBackend,
+
+    #[serde(default)]
+    pub format: LogFormat,
+
+    /// log file, required by the `file` backend
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+
+    /// default level: `off`, `error`, `warn`, `info`, `debug` or `trace`
+    #[serde(default = "default_log_level", deserialize_with = "deserialize_from_str")]
+    pub level: LevelFilter,
+
+    /// levels overriding `level` for a module path and everything below it,
+    /// e.g. `"quinn" = "warn"`
+    #[serde(default, deserialize_with = "deserialize_level_map")]
+    pub modules: BTreeMap<String, LevelFilter>,
+}
+
+fn default_log_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            backend: LogBackend::default(),
+            format: LogFormat::default(),
+            file: None,
+            level: default_log_level(),
+            modules: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
+
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
 impl Config {
@@ -37,15 +301,56 @@ impl Config {
         Ok(config)
     }
 
-    pub fn tls_acceptor(&self) -> Result<TlsAcceptor, Error> {
-        let tls_cert = self.server.tls_cert.clone();
-        let tls_key = self.server.tls_key.clone();
-        let certfile = std::fs::File::open(&tls_cert)
+    /// every certificate and key file referenced by the config
+    pub fn certificate_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        files.extend(self.server.tls_cert.clone());
+        files.extend(self.server.tls_key.clone());
+        for entry in &self.server.certificates {
+            files.push(entry.tls_cert.clone());
+            files.push(entry.tls_key.clone());
+        }
+        files
+    }
+
+    /// inspect every configured certificate, warning about those close to expiry.
+    /// expired or unreadable certificates are an error.
+    pub fn check_certificates(&self) -> Result<Vec<(PathBuf, CertificateInfo)>, Error> {
+        let window = Duration::from_secs(self.server.cert_expiry_warning_days * 86400);
+        let mut infos = Vec::new();
+        if let Some(self_signed) = &self.server.self_signed {
+            infos.push((PathBuf::from("(self-signed)"), CertificateInfo::from_der(&self_signed.cert_der)?));
+        }
+
+        let default_cert = self.server.tls_cert.as_ref().filter(|_| self.server.self_signed.is_none());
+        let files = default_cert.into_iter()
+            .chain(self.server.certificates.iter().map(|entry| &entry.tls_cert));
+        for path in files {
+            let pem = std::fs::read(path)
+                .map_err(|e| Error::new(ErrorKind::Other, format!("failed to open {}: {}", path.to_string_lossy(), e)))?;
+            let info = CertificateInfo::from_pem(&pem)
+                .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.to_string_lossy(), e)))?;
+            if info.is_expired() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{}: certificate {} expired on {}", path.to_string_lossy(), info.subject, info.not_after_string())));
+            }
+            if info.expires_within(window) {
+                log::warn!("Certificate {} ({}) expires in {} days, on {}", path.display(), info.subject, info.remaining().as_secs() / 86400, info.not_after_string());
+            }
+            infos.push((path.clone(), info));
+        }
+        Ok(infos)
+    }
+
+    fn load_certified_key(tls_cert: &Path, tls_key: &Path) -> Result<Arc<CertifiedKey>, Error> {
+        let certfile = std::fs::File::open(tls_cert)
             .map_err(|e| Error::new(ErrorKind::Other, format!("failed to open {}: {}", tls_cert.to_string_lossy(), e)))?;
         let mut reader = std::io::BufReader::new(certfile);
-        let certs = rustls_pemfile::certs(&mut reader).filter_map(|item| item.ok()).collect();
+        let certs: Vec<_> = rustls_pemfile::certs(&mut reader).filter_map(|item| item.ok()).collect();
+        if certs.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no certificate found in {}", tls_cert.to_string_lossy())));
+        }
 
-        let keyfile = std::fs::File::open(&tls_key)
+        let keyfile = std::fs::File::open(tls_key)
             .map_err(|e| Error::new(ErrorKind::Other, format!("failed to open {}: {}", tls_key.to_string_lossy(), e)))?;
         let mut reader = std::io::BufReader::new(keyfile);
         let key = rustls_pemfile::private_key(&mut reader)?;
@@ -55,15 +360,67 @@ impl Config {
             return Err(Error::new(ErrorKind::NotFound, "no private key found"));
         };
 
-        let mut server_config = rustls::ServerConfig::builder()
+        let info = CertificateInfo::from_der(&certs[0])
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", tls_cert.to_string_lossy(), e)))?;
+        if info.is_expired() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{}: certificate {} expired on {}", tls_cert.to_string_lossy(), info.subject, info.not_after_string())));
+        }
+
+        let provider = rustls::crypto::ring::default_provider();
+        let certified_key = CertifiedKey::from_der(certs, key, &provider)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", tls_cert.to_string_lossy(), e)))?;
+        Ok(Arc::new(certified_key))
+    }
+
+    fn rustls_server_config(&self) -> Result<rustls::ServerConfig, Error> {
+        let default = match (&self.server.self_signed, &self.server.tls_cert, &self.server.tls_key) {
+            (Some(self_signed), _, _) => self_signed.certified_key()?,
+            (None, Some(tls_cert), Some(tls_key)) => Self::load_certified_key(tls_cert, tls_key)?,
+            _ => return Err(Error::new(ErrorKind::NotFound, "tls_cert and tls_key must be set")),
+        };
+        let mut resolver = SniCertResolver::new(default);
+        for entry in &self.server.certificates {
+            let key = Self::load_certified_key(&entry.tls_cert, &entry.tls_key)?;
+            for name in &entry.server_names {
+                resolver.add(name, key.clone());
+            }
+        }
+
+        let server_config = rustls::ServerConfig::builder()
             .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            .with_cert_resolver(Arc::new(resolver));
+        Ok(server_config)
+    }
+
+    pub fn tls_acceptor(&self) -> Result<TlsAcceptor, Error> {
+        let mut server_config = self.rustls_server_config()?;
         server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
 
         let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
         Ok(tls_acceptor)
     }
+
+    /// QUIC server config with the same certificate chain, speaking HTTP/3 only;
+    /// 0-RTT stays off since early data can be replayed and `/upload` and
+    /// `/session` are not idempotent
+    pub fn quic_server_config(&self) -> Result<quinn::ServerConfig, Error> {
+        let mut server_config = self.rustls_server_config()?;
+        server_config.alpn_protocols = vec![b"h3".to_vec()];
+
+        let crypto = QuicServerConfig::try_from(server_config)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let mut quic_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        if let Some(secs) = self.server.idle_timeout {
+            let idle_timeout = quinn::IdleTimeout::try_from(Duration::from_secs(secs))
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            // freshly built, so the transport config is not shared yet and the
+            // rest of it stays as quinn set it up
+            if let Some(transport) = Arc::get_mut(&mut quic_config.transport) {
+                transport.max_idle_timeout(Some(idle_timeout));
+            }
+        }
+        Ok(quic_config)
+    }
 }
 
 impl FromStr for Config {
@@ -73,3 +430,40 @@ impl FromStr for Config {
         toml::from_str(s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_client_limits_are_refused() {
+        for limit in ["max_concurrent_tests", "max_bytes_per_minute", "max_requests_per_second"] {
+            let err = format!("[server.client_limits]\n{} = 0\n", limit).parse::<Config>().unwrap_err();
+            assert!(err.to_string().contains("greater than 0"), "{}: {}", limit, err);
+            let config: Config = format!("[server.client_limits]\n{} = 5\n", limit).parse().unwrap();
+            assert!(format!("{:?}", config.server.client_limits).contains("Some(5)"));
+        }
+        let config: Config = "[server.client_limits]\n".parse().unwrap();
+        assert!(config.server.client_limits.max_bytes_per_minute.is_none());
+    }
+
+    #[test]
+    fn zero_test_bounds_are_refused() {
+        for setting in ["max_upload_bytes", "max_test_duration", "idle_timeout"] {
+            let err = format!("[server]\n{} = 0\n", setting).parse::<Config>().unwrap_err();
+            assert!(err.to_string().contains("greater than 0"), "{}: {}", setting, err);
+        }
+        let config: Config = "[server]\nidle_timeout = 30\n".parse().unwrap();
+        assert_eq!(config.server.idle_timeout, Some(30));
+    }
+
+    #[test]
+    fn zero_global_limits_are_refused() {
+        for limit in ["max_connections_per_listener", "max_concurrent_tests", "max_egress_bits_per_second", "max_ingress_bits_per_second"] {
+            let err = format!("[server.global_limits]\n{} = 0\n", limit).parse::<Config>().unwrap_err();
+            assert!(err.to_string().contains("greater than 0"), "{}: {}", limit, err);
+            let config: Config = format!("[server.global_limits]\n{} = 5\n", limit).parse().unwrap();
+            assert!(format!("{:?}", config.server.global_limits).contains("Some(5)"));
+        }
+    }
+}
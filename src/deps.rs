@@ -16,6 +16,8 @@ pub use x509_parser;
 pub use serde_json;
 pub use http_body;
 pub use socket2;
+pub use h3;
+pub use h3_quinn;
 pub use rustls_pemfile;
 
 #[cfg(feature = "config")]
@@ -23,3 +25,6 @@ pub use serde;
 
 #[cfg(feature = "config")]
 pub use toml;
+
+#[cfg(feature = "client")]
+pub use rustls_platform_verifier;
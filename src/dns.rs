@@ -2,6 +2,7 @@
 use crate::deps;
 
 use std::net::{
+    IpAddr,
     Ipv4Addr,
     Ipv6Addr,
     SocketAddr,
@@ -21,6 +22,32 @@ pub struct HostAddrs {
     pub addrs_v6: Vec<Ipv6Addr>,
 }
 
+impl HostAddrs {
+    pub fn from_ip(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(v4) => Self { addrs_v4: vec![v4], addrs_v6: Vec::new() },
+            IpAddr::V6(v6) => Self { addrs_v4: Vec::new(), addrs_v6: vec![v6] },
+        }
+    }
+
+    /// IPv6 first, then alternating families (RFC 8305 section 4)
+    pub fn interleaved(&self, port: u16) -> Vec<SocketAddr> {
+        let mut v6 = self.addrs_v6.iter();
+        let mut v4 = self.addrs_v4.iter();
+        let mut addrs = Vec::with_capacity(self.addrs_v6.len() + self.addrs_v4.len());
+        loop {
+            let a = v6.next().map(|ip| SocketAddr::new(IpAddr::V6(*ip), port));
+            let b = v4.next().map(|ip| SocketAddr::new(IpAddr::V4(*ip), port));
+            if a.is_none() && b.is_none() {
+                break;
+            }
+            addrs.extend(a);
+            addrs.extend(b);
+        }
+        addrs
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DnsResolver {
     pool: Arc<RwLock<ThreadPool>>,
@@ -79,3 +106,37 @@ impl DnsResolver {
         rx.await.map_err(|_| Error::new(std::io::ErrorKind::Interrupted, "thread interrupted"))?
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaved_starts_with_ipv6_and_alternates() {
+        let host = HostAddrs {
+            addrs_v4: vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)],
+            addrs_v6: vec!["2001:db8::1".parse().unwrap(), "2001:db8::2".parse().unwrap(), "2001:db8::3".parse().unwrap()],
+        };
+        let addrs: Vec<String> = host.interleaved(443).iter().map(SocketAddr::to_string).collect();
+        assert_eq!(addrs, [
+            "[2001:db8::1]:443",
+            "192.0.2.1:443",
+            "[2001:db8::2]:443",
+            "192.0.2.2:443",
+            "[2001:db8::3]:443",
+        ]);
+
+        let v4_only = HostAddrs { addrs_v4: host.addrs_v4.clone(), addrs_v6: Vec::new() };
+        assert_eq!(v4_only.interleaved(80).len(), 2);
+        assert!(v4_only.interleaved(80).iter().all(SocketAddr::is_ipv4));
+    }
+
+    #[test]
+    fn from_ip_sorts_by_family() {
+        let v4 = HostAddrs::from_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!((v4.addrs_v4.len(), v4.addrs_v6.len()), (1, 0));
+        let v6 = HostAddrs::from_ip(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!((v6.addrs_v4.len(), v6.addrs_v6.len()), (0, 1));
+        assert_eq!(v6.interleaved(8443), [SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8443)]);
+    }
+}
@@ -5,6 +5,7 @@ use std::net::{
     SocketAddr,
 };
 
+use std::fmt::{Display, Formatter};
 use std::time::Duration;
 
 pub const V6_UNSPECIFIED: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
@@ -13,4 +14,31 @@ pub const fn socket_addr_unspecified(port: u16) -> SocketAddr {
     SocketAddr::new(V6_UNSPECIFIED, port)
 }
 
+/// delay between staggered connection attempts (RFC 8305 section 5)
 pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    /// v4-mapped IPv6 addresses count as IPv4
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr.ip() {
+            IpAddr::V4(_) => AddressFamily::V4,
+            IpAddr::V6(ip) if ip.to_ipv4_mapped().is_some() => AddressFamily::V4,
+            IpAddr::V6(_) => AddressFamily::V6,
+        }
+    }
+}
+
+impl Display for AddressFamily {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressFamily::V4 => write!(f, "IPv4"),
+            AddressFamily::V6 => write!(f, "IPv6"),
+        }
+    }
+}
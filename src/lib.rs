@@ -7,12 +7,32 @@ pub mod bin_deps;
 #[cfg(feature = "config")]
 pub mod config;
 
+#[cfg(feature = "config")]
+pub mod reload;
+
+#[cfg(feature = "build-binaries")]
+pub mod logging;
+
 #[cfg(feature = "server")]
 pub mod server;
 
+#[cfg(feature = "server")]
+pub mod metrics;
+
+#[cfg(feature = "server")]
+pub mod access_log;
+
+#[cfg(feature = "server")]
+pub mod shutdown;
+
+#[cfg(feature = "server")]
+pub mod limits;
+
 #[cfg(feature = "client")]
 pub mod client;
 
+pub mod proto;
+pub mod time;
 pub mod inet;
 pub mod udp;
 pub mod tcp;
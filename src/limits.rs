@@ -0,0 +1,678 @@
+
+use crate::deps;
+
+use deps::hyper;
+use deps::log;
+use deps::parking_lot::Mutex;
+use deps::tokio;
+
+use hyper::body::{Body, Buf, Frame, SizeHint};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Sleep;
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// how often idle client entries are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `rate` tokens per second up to `capacity`; taking may run into debt,
+/// which has to be paid back before anything is available again
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// a full bucket
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self { rate, capacity, tokens: capacity, updated: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    /// take `n` tokens even if that leaves the bucket in debt
+    pub fn take(&mut self, now: Instant, n: f64) {
+        self.refill(now);
+        self.tokens -= n;
+    }
+
+    /// time until `n` tokens are available; zero if they are now and
+    /// `Duration::MAX` if a bucket without refill never gets them
+    pub fn wait_time(&mut self, now: Instant, n: f64) -> Duration {
+        self.refill(now);
+        if self.tokens >= n {
+            Duration::ZERO
+        } else if self.rate <= 0.0 {
+            Duration::MAX
+        } else {
+            Duration::try_from_secs_f64((n - self.tokens) / self.rate).unwrap_or(Duration::MAX)
+        }
+    }
+
+    /// take `n` tokens if available, or report how long until they are
+    pub fn try_take(&mut self, now: Instant, n: f64) -> Result<(), Duration> {
+        let wait = self.wait_time(now, n);
+        if wait.is_zero() {
+            self.tokens -= n;
+            Ok(())
+        } else {
+            Err(wait)
+        }
+    }
+}
+
+/// per-client limits; `None` leaves a dimension unlimited and 0 refuses
+/// every request the limit applies to
+#[derive(Debug, Clone, Default)]
+pub struct ClientLimits {
+    /// uploads, downloads and echo streams running at once
+    pub max_concurrent_tests: Option<usize>,
+    /// test body bytes in either direction, refilled continuously; a client
+    /// over it gets no new tests and its running ones slow to the refill rate
+    pub max_bytes_per_minute: Option<u64>,
+    /// requests of any kind, allowing bursts of the same size
+    pub max_requests_per_second: Option<u32>,
+}
+
+impl ClientLimits {
+    pub fn with_max_concurrent_tests(mut self, max: usize) -> Self {
+        self.max_concurrent_tests = Some(max);
+        self
+    }
+
+    pub fn with_max_bytes_per_minute(mut self, max: u64) -> Self {
+        self.max_bytes_per_minute = Some(max);
+        self
+    }
+
+    pub fn with_max_requests_per_second(mut self, max: u32) -> Self {
+        self.max_requests_per_second = Some(max);
+        self
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_concurrent_tests.is_none()
+            && self.max_bytes_per_minute.is_none()
+            && self.max_requests_per_second.is_none()
+    }
+}
+
+/// why a request was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// a server-wide limit rather than one of the client's
+    ServerBusy,
+    ConcurrentTests,
+    BytesPerMinute { retry_after: Duration },
+    RequestsPerSecond { retry_after: Duration },
+}
+
+impl LimitExceeded {
+    /// value for `Retry-After`, in whole seconds
+    pub fn retry_after_secs(&self) -> u64 {
+        match self {
+            LimitExceeded::ServerBusy | LimitExceeded::ConcurrentTests => 1,
+            LimitExceeded::BytesPerMinute { retry_after } | LimitExceeded::RequestsPerSecond { retry_after } => {
+                std::cmp::max(1, retry_after.as_secs().saturating_add(u64::from(retry_after.subsec_nanos() > 0)))
+            }
+        }
+    }
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::ServerBusy => write!(f, "server busy"),
+            LimitExceeded::ConcurrentTests => write!(f, "too many concurrent tests"),
+            LimitExceeded::BytesPerMinute { .. } => write!(f, "too many bytes per minute"),
+            LimitExceeded::RequestsPerSecond { .. } => write!(f, "too many requests per second"),
+        }
+    }
+}
+
+/// clients are IPv4 addresses or IPv6 /64 prefixes, since a single host
+/// usually has a whole /64 to pick addresses from
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let segments = v6.segments();
+                IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], 0, 0, 0, 0))
+            }
+        },
+    }
+}
+
+#[derive(Debug)]
+struct ClientState {
+    active_tests: usize,
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl ClientState {
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.active_tests == 0
+            && self.requests.as_mut().map_or(true, |b| b.is_full(now))
+            && self.bytes.as_mut().map_or(true, |b| b.is_full(now))
+    }
+}
+
+/// enforces `ClientLimits` across all listeners sharing it
+#[derive(Debug)]
+pub struct ClientLimiter {
+    limits: ClientLimits,
+    clients: Mutex<HashMap<IpAddr, Arc<Mutex<ClientState>>>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl ClientLimiter {
+    pub fn new(limits: ClientLimits) -> Self {
+        Self {
+            limits,
+            clients: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn limits(&self) -> &ClientLimits {
+        &self.limits
+    }
+
+    fn state(&self, key: IpAddr, now: Instant) -> Arc<Mutex<ClientState>> {
+        let mut clients = self.clients.lock();
+        {
+            let mut last_sweep = self.last_sweep.lock();
+            if now.saturating_duration_since(*last_sweep) >= SWEEP_INTERVAL {
+                *last_sweep = now;
+                // entries without permits whose buckets have refilled carry no state
+                clients.retain(|_, state| Arc::strong_count(state) > 1 || !state.lock().is_idle(now));
+            }
+        }
+        clients.entry(key).or_insert_with(|| {
+            Arc::new(Mutex::new(ClientState {
+                active_tests: 0,
+                requests: self.limits.max_requests_per_second.map(|rps| TokenBucket::new(rps as f64, rps as f64)),
+                bytes: self.limits.max_bytes_per_minute.map(|bpm| TokenBucket::new(bpm as f64 / 60.0, bpm as f64)),
+            }))
+        }).clone()
+    }
+
+    /// account a request from `ip`; tests also count against the concurrency
+    /// and byte limits, `expected_bytes` being their size when known upfront
+    pub fn admit(&self, ip: IpAddr, test: bool, expected_bytes: Option<u64>) -> Result<ClientPermit, LimitExceeded> {
+        let now = Instant::now();
+        let state = self.state(client_key(ip), now);
+        {
+            let mut client = state.lock();
+            if let Some(requests) = &mut client.requests {
+                requests.try_take(now, 1.0).map_err(|retry_after| LimitExceeded::RequestsPerSecond { retry_after })?;
+            }
+            if test {
+                if let Some(max) = self.limits.max_concurrent_tests {
+                    if client.active_tests >= max {
+                        return Err(LimitExceeded::ConcurrentTests);
+                    }
+                }
+                if let (Some(bytes), Some(max)) = (&mut client.bytes, self.limits.max_bytes_per_minute) {
+                    // a transfer larger than the whole budget would never be admitted
+                    let needed = expected_bytes.unwrap_or(1).clamp(1, max.max(1)) as f64;
+                    let retry_after = bytes.wait_time(now, needed);
+                    if expected_bytes.map_or(false, |n| n > max) || retry_after > Duration::ZERO {
+                        return Err(LimitExceeded::BytesPerMinute { retry_after: std::cmp::max(retry_after, Duration::from_secs(1)) });
+                    }
+                }
+                client.active_tests += 1;
+            }
+        }
+        Ok(ClientPermit { state, test })
+    }
+}
+
+/// held for the lifetime of an admitted request
+#[derive(Debug)]
+pub struct ClientPermit {
+    state: Arc<Mutex<ClientState>>,
+    test: bool,
+}
+
+impl ClientPermit {
+    /// the client's byte budget for throttling test bodies; `None` without a byte limit
+    pub fn byte_budget(self: &Arc<Self>) -> Option<Arc<dyn Throttle>> {
+        if self.state.lock().bytes.is_some() {
+            Some(self.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl Throttle for ClientPermit {
+    fn charge(&self, n: u64) {
+        if let Some(bytes) = &mut self.state.lock().bytes {
+            bytes.take(Instant::now(), n as f64);
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        match &mut self.state.lock().bytes {
+            Some(bytes) => bytes.wait_time(Instant::now(), 0.0),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        if self.test {
+            self.state.lock().active_tests -= 1;
+        }
+    }
+}
+
+/// burst allowed by a `BandwidthLimiter`, as time at the full rate
+const BANDWIDTH_BURST: Duration = Duration::from_millis(100);
+
+/// smallest burst, so a single frame never waits on its own
+const MIN_BANDWIDTH_BURST_BYTES: f64 = 65536.0;
+
+/// server-wide limits; `None` leaves a dimension unlimited and 0 lets
+/// nothing through
+#[derive(Debug, Clone, Default)]
+pub struct GlobalLimits {
+    /// open connections per listener; TCP listeners stop accepting, QUIC refuses
+    pub max_connections_per_listener: Option<usize>,
+    /// uploads, downloads and echo streams running at once across all clients
+    pub max_concurrent_tests: Option<usize>,
+    /// response body bits per second across all listeners
+    pub max_egress_bits_per_second: Option<u64>,
+    /// request body bits per second across all listeners
+    pub max_ingress_bits_per_second: Option<u64>,
+}
+
+impl GlobalLimits {
+    pub fn with_max_connections_per_listener(mut self, max: usize) -> Self {
+        self.max_connections_per_listener = Some(max);
+        self
+    }
+
+    pub fn with_max_concurrent_tests(mut self, max: usize) -> Self {
+        self.max_concurrent_tests = Some(max);
+        self
+    }
+
+    pub fn with_max_egress_bits_per_second(mut self, max: u64) -> Self {
+        self.max_egress_bits_per_second = Some(max);
+        self
+    }
+
+    pub fn with_max_ingress_bits_per_second(mut self, max: u64) -> Self {
+        self.max_ingress_bits_per_second = Some(max);
+        self
+    }
+}
+
+/// a byte budget shared by bodies, which are held back while it is in debt
+pub trait Throttle: Send + Sync {
+    /// account `n` bytes that have just been moved
+    fn charge(&self, n: u64);
+
+    /// time until the bytes already moved are paid for
+    fn delay(&self) -> Duration;
+}
+
+/// aggregate byte rate shared by any number of bodies
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bucket: Mutex<TokenBucket>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bits_per_second: u64) -> Self {
+        let rate = bits_per_second as f64 / 8.0;
+        let capacity = (rate * BANDWIDTH_BURST.as_secs_f64()).max(MIN_BANDWIDTH_BURST_BYTES);
+        Self { bucket: Mutex::new(TokenBucket::new(rate, capacity)) }
+    }
+}
+
+impl Throttle for BandwidthLimiter {
+    fn charge(&self, n: u64) {
+        self.bucket.lock().take(Instant::now(), n as f64);
+    }
+
+    fn delay(&self) -> Duration {
+        self.bucket.lock().wait_time(Instant::now(), 0.0)
+    }
+}
+
+/// slots for the open connections of one listener
+#[derive(Debug, Clone)]
+pub struct ConnectionSlots(Option<Arc<Semaphore>>);
+
+impl ConnectionSlots {
+    /// wait for a free slot; `None` when connections are unlimited
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.0 {
+            // the semaphore is never closed
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    /// a free slot if there is one; `Ok(None)` when connections are unlimited
+    pub fn try_acquire(&self) -> Result<Option<OwnedSemaphorePermit>, LimitExceeded> {
+        match &self.0 {
+            Some(semaphore) => semaphore.clone().try_acquire_owned()
+                .map(Some)
+                .map_err(|_| LimitExceeded::ServerBusy),
+            None => Ok(None),
+        }
+    }
+}
+
+/// enforces `GlobalLimits`; shared by all listeners through `ServerOptions`
+#[derive(Debug, Default)]
+pub struct GlobalLimiter {
+    limits: GlobalLimits,
+    tests: Option<Arc<Semaphore>>,
+    egress: Option<Arc<BandwidthLimiter>>,
+    ingress: Option<Arc<BandwidthLimiter>>,
+}
+
+impl GlobalLimiter {
+    pub fn new(limits: GlobalLimits) -> Self {
+        Self {
+            tests: limits.max_concurrent_tests.map(|max| Arc::new(Semaphore::new(max))),
+            egress: limits.max_egress_bits_per_second.map(|bps| Arc::new(BandwidthLimiter::new(bps))),
+            ingress: limits.max_ingress_bits_per_second.map(|bps| Arc::new(BandwidthLimiter::new(bps))),
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> &GlobalLimits {
+        &self.limits
+    }
+
+    /// fresh slots for a listener starting up
+    pub fn connection_slots(&self) -> ConnectionSlots {
+        if self.limits.max_connections_per_listener == Some(0) {
+            log::warn!("max_connections_per_listener is 0, listeners will not accept any connection");
+        }
+        ConnectionSlots(self.limits.max_connections_per_listener.map(|max| Arc::new(Semaphore::new(max))))
+    }
+
+    /// a slot for one more test, held until it is finished
+    pub fn start_test(&self) -> Result<Option<OwnedSemaphorePermit>, LimitExceeded> {
+        match &self.tests {
+            Some(tests) => tests.clone().try_acquire_owned()
+                .map(Some)
+                .map_err(|_| LimitExceeded::ServerBusy),
+            None => Ok(None),
+        }
+    }
+
+    /// egress limit shared by all response bodies of tests
+    pub fn egress(&self) -> Option<Arc<dyn Throttle>> {
+        self.egress.clone().map(|limiter| limiter as Arc<dyn Throttle>)
+    }
+
+    /// ingress limit shared by all request bodies of tests
+    pub fn ingress(&self) -> Option<Arc<dyn Throttle>> {
+        self.ingress.clone().map(|limiter| limiter as Arc<dyn Throttle>)
+    }
+}
+
+/// holds back the next frame of a body while its `Throttle` is in debt
+pub struct ThrottledBody<B> {
+    inner: B,
+    limiter: Option<Arc<dyn Throttle>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> ThrottledBody<B> {
+    /// `None` passes frames straight through
+    pub fn new(inner: B, limiter: Option<Arc<dyn Throttle>>) -> Self {
+        Self { inner, limiter, delay: None }
+    }
+}
+
+impl<B: Body + Unpin> Body for ThrottledBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let limiter = match &this.limiter {
+            Some(limiter) => limiter,
+            None => return Pin::new(&mut this.inner).poll_frame(cx),
+        };
+        loop {
+            if let Some(delay) = &mut this.delay {
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.delay = None;
+            }
+            let wait = limiter.delay();
+            if wait.is_zero() {
+                break;
+            }
+            this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                limiter.charge(data.remaining() as u64);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use deps::http_body_util::{BodyExt, StreamBody};
+    use hyper::body::Bytes;
+    use std::convert::Infallible;
+    use std::net::Ipv4Addr;
+
+    fn secs(n: f64) -> Duration {
+        Duration::from_secs_f64(n)
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 100.0);
+        assert!(bucket.is_full(start));
+        assert_eq!(bucket.try_take(start, 60.0), Ok(()));
+        assert_eq!(bucket.try_take(start, 60.0), Err(secs(2.0)));
+        assert!(!bucket.is_full(start + secs(3.0)));
+        assert_eq!(bucket.try_take(start + secs(2.0), 60.0), Ok(()));
+        assert!(bucket.is_full(start + secs(100.0)));
+        assert_eq!(bucket.wait_time(start + secs(100.0), 100.0), Duration::ZERO);
+        assert_eq!(bucket.wait_time(start + secs(100.0), 110.0), secs(1.0));
+    }
+
+    #[test]
+    fn token_bucket_debt_is_paid_back() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 100.0);
+        bucket.take(start, 150.0);
+        assert_eq!(bucket.wait_time(start, 0.0), secs(5.0));
+        assert_eq!(bucket.wait_time(start + secs(4.0), 0.0), secs(1.0));
+        assert_eq!(bucket.wait_time(start + secs(5.0), 0.0), Duration::ZERO);
+        assert!(bucket.try_take(start + secs(5.0), 1.0).is_err());
+    }
+
+    #[test]
+    fn token_bucket_without_refill_never_fills_up() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(0.0, 0.0);
+        assert_eq!(bucket.wait_time(start, 0.0), Duration::ZERO);
+        assert_eq!(bucket.wait_time(start + secs(100.0), 1.0), Duration::MAX);
+        assert_eq!(bucket.try_take(start, 1.0), Err(Duration::MAX));
+        bucket.take(start, 10.0);
+        assert_eq!(bucket.wait_time(start + secs(100.0), 0.0), Duration::MAX);
+        assert!(LimitExceeded::RequestsPerSecond { retry_after: Duration::MAX }.retry_after_secs() > 0);
+    }
+
+    #[test]
+    fn client_key_groups_ipv6_by_64() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(client_key(v4), v4);
+
+        let a: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:ffff::1".parse().unwrap();
+        let c: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+        assert_eq!(client_key(a), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+        assert_eq!(client_key(a), client_key(b));
+        assert_ne!(client_key(a), client_key(c));
+
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(client_key(mapped), v4);
+    }
+
+    #[test]
+    fn concurrent_tests_are_limited() {
+        let limiter = ClientLimiter::new(ClientLimits::default().with_max_concurrent_tests(1));
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let permit = limiter.admit(ip, true, None).unwrap();
+        assert_eq!(limiter.admit(ip, true, None).unwrap_err(), LimitExceeded::ConcurrentTests);
+        // probes do not count
+        limiter.admit(ip, false, None).unwrap();
+        // another client has its own count
+        limiter.admit(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), true, None).unwrap();
+        drop(permit);
+        limiter.admit(ip, true, None).unwrap();
+    }
+
+    #[test]
+    fn requests_are_limited() {
+        let limiter = ClientLimiter::new(ClientLimits::default().with_max_requests_per_second(2));
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        limiter.admit(ip, false, None).unwrap();
+        limiter.admit(ip, true, None).unwrap();
+        assert!(matches!(limiter.admit(ip, false, None), Err(LimitExceeded::RequestsPerSecond { .. })));
+    }
+
+    #[test]
+    fn byte_budget_in_debt_refuses_tests() {
+        let limiter = ClientLimiter::new(ClientLimits::default().with_max_bytes_per_minute(600));
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(matches!(limiter.admit(ip, true, Some(601)), Err(LimitExceeded::BytesPerMinute { .. })));
+
+        let permit = Arc::new(limiter.admit(ip, true, None).unwrap());
+        let budget = permit.byte_budget().unwrap();
+        assert_eq!(budget.delay(), Duration::ZERO);
+        budget.charge(700);
+        // 100 bytes of debt at 10 bytes per second
+        let delay = budget.delay();
+        assert!(delay > secs(9.0) && delay <= secs(10.0), "{:?}", delay);
+        match limiter.admit(ip, true, None) {
+            Err(e @ LimitExceeded::BytesPerMinute { .. }) => assert!(e.retry_after_secs() >= 10),
+            other => panic!("{:?}", other),
+        }
+        // probes still get through
+        limiter.admit(ip, false, None).unwrap();
+
+        let unlimited = ClientLimiter::new(ClientLimits::default().with_max_concurrent_tests(1));
+        assert!(Arc::new(unlimited.admit(ip, true, None).unwrap()).byte_budget().is_none());
+    }
+
+    #[test]
+    fn zero_client_limits_refuse_without_panicking() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let limiter = ClientLimiter::new(ClientLimits::default().with_max_bytes_per_minute(0));
+        for expected in [None, Some(0), Some(1000)] {
+            assert!(matches!(limiter.admit(ip, true, expected), Err(LimitExceeded::BytesPerMinute { .. })));
+        }
+        limiter.admit(ip, false, None).unwrap();
+
+        let limiter = ClientLimiter::new(ClientLimits::default().with_max_requests_per_second(0));
+        match limiter.admit(ip, false, None) {
+            Err(e @ LimitExceeded::RequestsPerSecond { .. }) => assert!(e.retry_after_secs() >= 1),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(limiter.admit(ip, true, None), Err(LimitExceeded::RequestsPerSecond { .. })));
+
+        let limiter = ClientLimiter::new(ClientLimits::default().with_max_concurrent_tests(0));
+        assert_eq!(limiter.admit(ip, true, None).unwrap_err(), LimitExceeded::ConcurrentTests);
+        limiter.admit(ip, false, None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttled_body_holds_back_frames_in_debt() {
+        // 1 MB/s with a burst of 100 ms, 100000 bytes
+        let limiter: Arc<dyn Throttle> = Arc::new(BandwidthLimiter::new(8_000_000));
+        let frames = (0..5).map(|_| Ok::<_, Infallible>(Frame::data(Bytes::from_static(&[0u8; 65536]))));
+        let body = ThrottledBody::new(StreamBody::new(futures::stream::iter(frames)), Some(limiter));
+        let start = Instant::now();
+        let collected = body.collect().await.unwrap().to_bytes();
+        assert_eq!(collected.len(), 5 * 65536);
+        // everything beyond the burst waits for its share of the rate
+        assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn zero_global_limits_let_nothing_through() {
+        let limiter = GlobalLimiter::new(GlobalLimits::default()
+            .with_max_connections_per_listener(0)
+            .with_max_concurrent_tests(0));
+        assert_eq!(limiter.connection_slots().try_acquire().unwrap_err(), LimitExceeded::ServerBusy);
+        assert_eq!(limiter.start_test().unwrap_err(), LimitExceeded::ServerBusy);
+
+        let bandwidth = BandwidthLimiter::new(0);
+        assert_eq!(bandwidth.delay(), Duration::ZERO);
+        bandwidth.charge(MIN_BANDWIDTH_BURST_BYTES as u64 + 1);
+        assert_eq!(bandwidth.delay(), Duration::MAX);
+    }
+
+    #[tokio::test]
+    async fn throttled_body_at_zero_bandwidth_stalls_after_the_burst() {
+        let limiter = GlobalLimiter::new(GlobalLimits::default()
+            .with_max_egress_bits_per_second(0)
+            .with_max_ingress_bits_per_second(0));
+        for throttle in [limiter.egress(), limiter.ingress()] {
+            let frames = (0..3).map(|_| Ok::<_, Infallible>(Frame::data(Bytes::from_static(&[0u8; 65536]))));
+            let mut body = ThrottledBody::new(StreamBody::new(futures::stream::iter(frames)), throttle);
+            // the burst pays for the first frame and the second may still start;
+            // after that the bucket is in debt for good
+            body.frame().await.unwrap().unwrap();
+            body.frame().await.unwrap().unwrap();
+            assert!(tokio::time::timeout(Duration::from_millis(100), body.frame()).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,152 @@
+
+use crate::deps;
+use crate::bin_deps;
+use crate::config::{LogBackend, LogFormat, LoggingConfig};
+use crate::time::civil_from_unix;
+
+use deps::log;
+use deps::parking_lot::Mutex;
+use deps::serde_json;
+use bin_deps::syslog;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use syslog::{BasicLogger, Facility, Formatter3164};
+
+use std::fs::{File, OpenOptions};
+use std::io::{
+    Error,
+    ErrorKind,
+    LineWriter,
+    Write,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+enum Sink {
+    Syslog(BasicLogger),
+    Stderr(LogFormat),
+    File(Mutex<LineWriter<File>>, LogFormat),
+}
+
+/// `log` backend with per-module level filters
+struct Logger {
+    level: LevelFilter,
+    /// longest module path first, so the most specific entry wins
+    modules: Vec<(String, LevelFilter)>,
+    sink: Sink,
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .find(|(module, _)| target == module || (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::")))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.level, std::cmp::max)
+    }
+}
+
+/// RFC 3339 UTC timestamp with milliseconds
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day, hour, minute, second) = civil_from_unix(since_epoch.as_secs());
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, since_epoch.subsec_millis())
+}
+
+fn format_record(record: &Record, format: LogFormat) -> String {
+    let time = format_time(SystemTime::now());
+    match format {
+        LogFormat::Human => format!("{} {:<5} {}: {}\n", time, record.level(), record.target(), record.args()),
+        LogFormat::Json => {
+            let mut line = serde_json::json!({
+                "time": time,
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            }).to_string();
+            line.push('\n');
+            line
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match &self.sink {
+            Sink::Syslog(logger) => logger.log(record),
+            Sink::Stderr(format) => {
+                let _ = std::io::stderr().write_all(format_record(record, *format).as_bytes());
+            }
+            Sink::File(file, format) => {
+                let _ = file.lock().write_all(format_record(record, *format).as_bytes());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        match &self.sink {
+            Sink::Syslog(logger) => logger.flush(),
+            Sink::Stderr(_) => {
+                let _ = std::io::stderr().flush();
+            }
+            Sink::File(file, _) => {
+                let _ = file.lock().flush();
+            }
+        }
+    }
+}
+
+fn syslog_sink() -> Result<Sink, syslog::Error> {
+    let formatter = Formatter3164 {
+        facility: Facility::LOG_DAEMON,
+        hostname: None,
+        process: std::env::args().next().unwrap_or_default(),
+        pid: std::process::id(),
+    };
+    syslog::unix(formatter).map(|logger| Sink::Syslog(BasicLogger::new(logger)))
+}
+
+/// install the global logger described by `config`; `verbose` raises the
+/// default level to at least `debug`
+pub fn init(config: &LoggingConfig, verbose: bool) -> Result<(), Error> {
+    let sink = match config.backend {
+        LogBackend::Syslog => match syslog_sink() {
+            Ok(sink) => sink,
+            Err(e) => {
+                eprintln!("syslog unavailable ({}), logging to stderr", e);
+                Sink::Stderr(config.format)
+            }
+        },
+        LogBackend::Stderr => Sink::Stderr(config.format),
+        LogBackend::File => {
+            let path = config.file.as_ref()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "logging backend \"file\" requires logging.file"))?;
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| Error::new(e.kind(), format!("failed to open {}: {}", path.display(), e)))?;
+            Sink::File(Mutex::new(LineWriter::new(file)), config.format)
+        }
+    };
+
+    let mut modules: Vec<_> = config.modules.iter()
+        .map(|(module, level)| (module.clone(), *level))
+        .collect();
+    modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+
+    let level = if verbose { std::cmp::max(config.level, LevelFilter::Debug) } else { config.level };
+    let logger = Logger { level, modules, sink };
+    let max_level = logger.max_level();
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
@@ -0,0 +1,319 @@
+
+use crate::deps;
+use crate::proto::HttpVersion;
+
+use deps::hyper;
+use deps::parking_lot::{Mutex, RwLock};
+
+use hyper::body::{Body, Buf, Frame, SizeHint};
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// upper bounds of the request duration histogram, in seconds; downloads and
+/// uploads of a speed test run for seconds, probes for milliseconds
+const DURATION_BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// cumulative histogram over `DURATION_BUCKETS`
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        for (bucket, le) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            if secs <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, le) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// counters of one listening socket
+#[derive(Debug)]
+pub struct ListenerMetrics {
+    /// `plain`, `tls` or `quic`
+    kind: &'static str,
+    addr: SocketAddr,
+    pub accepted: Counter,
+    pub active: Gauge,
+    /// failed TLS handshakes, or QUIC handshakes for `quic`
+    pub handshake_failures: Counter,
+}
+
+impl ListenerMetrics {
+    /// count an accepted connection, active until the guard is dropped
+    pub fn connection(self: &Arc<Self>) -> ActiveConnection {
+        self.accepted.add(1);
+        self.active.add(1);
+        ActiveConnection(self.clone())
+    }
+
+    fn labels(&self) -> String {
+        format!("listener=\"{}\",address=\"{}\"", self.kind, self.addr)
+    }
+}
+
+/// decrements the active connection gauge on drop
+#[derive(Debug)]
+pub struct ActiveConnection(Arc<ListenerMetrics>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.add(-1);
+    }
+}
+
+/// everything exposed on `/metrics`, shared by all listeners
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    listeners: RwLock<Vec<Arc<ListenerMetrics>>>,
+    /// indexed like `HttpVersion::ALL`
+    uploaded_bytes: [Counter; 3],
+    downloaded_bytes: [Counter; 3],
+    request_duration: [Histogram; 3],
+    /// access log records dropped because the writer fell behind
+    pub access_log_dropped: Counter,
+}
+
+fn version_index(http_version: HttpVersion) -> usize {
+    match http_version {
+        HttpVersion::Http1 => 0,
+        HttpVersion::Http2 => 1,
+        HttpVersion::Http3 => 2,
+    }
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// register a listener; called once by each server as it starts
+    pub fn listener(&self, kind: &'static str, addr: SocketAddr) -> Arc<ListenerMetrics> {
+        let listener = Arc::new(ListenerMetrics {
+            kind,
+            addr,
+            accepted: Counter::default(),
+            active: Gauge::default(),
+            handshake_failures: Counter::default(),
+        });
+        self.listeners.write().push(listener.clone());
+        listener
+    }
+
+    pub fn uploaded_bytes(&self, http_version: HttpVersion) -> &Counter {
+        &self.uploaded_bytes[version_index(http_version)]
+    }
+
+    pub fn downloaded_bytes(&self, http_version: HttpVersion) -> &Counter {
+        &self.downloaded_bytes[version_index(http_version)]
+    }
+
+    pub fn request_duration(&self, http_version: HttpVersion) -> &Histogram {
+        &self.request_duration[version_index(http_version)]
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let listeners = self.listeners.read();
+
+        out.push_str("# HELP quic_speed_connections_accepted_total Connections accepted per listener.\n");
+        out.push_str("# TYPE quic_speed_connections_accepted_total counter\n");
+        for l in listeners.iter() {
+            let _ = writeln!(out, "quic_speed_connections_accepted_total{{{}}} {}", l.labels(), l.accepted.get());
+        }
+        out.push_str("# HELP quic_speed_connections_active Connections currently open per listener.\n");
+        out.push_str("# TYPE quic_speed_connections_active gauge\n");
+        for l in listeners.iter() {
+            let _ = writeln!(out, "quic_speed_connections_active{{{}}} {}", l.labels(), l.active.get());
+        }
+        out.push_str("# HELP quic_speed_handshake_failures_total Failed TLS (or QUIC) handshakes per listener.\n");
+        out.push_str("# TYPE quic_speed_handshake_failures_total counter\n");
+        for l in listeners.iter().filter(|l| l.kind != "plain") {
+            let _ = writeln!(out, "quic_speed_handshake_failures_total{{{}}} {}", l.labels(), l.handshake_failures.get());
+        }
+
+        out.push_str("# HELP quic_speed_bytes_total Body bytes moved per direction and HTTP version.\n");
+        out.push_str("# TYPE quic_speed_bytes_total counter\n");
+        for http_version in HttpVersion::ALL {
+            let _ = writeln!(out, "quic_speed_bytes_total{{direction=\"upload\",http_version=\"{}\"}} {}", http_version, self.uploaded_bytes(http_version).get());
+            let _ = writeln!(out, "quic_speed_bytes_total{{direction=\"download\",http_version=\"{}\"}} {}", http_version, self.downloaded_bytes(http_version).get());
+        }
+
+        out.push_str("# HELP quic_speed_request_duration_seconds Time from request to the end of the response body.\n");
+        out.push_str("# TYPE quic_speed_request_duration_seconds histogram\n");
+        for http_version in HttpVersion::ALL {
+            let labels = format!("http_version=\"{}\"", http_version);
+            self.request_duration(http_version).write(&mut out, "quic_speed_request_duration_seconds", &labels);
+        }
+
+        out.push_str("# HELP quic_speed_access_log_dropped_total Access log records dropped because the writer fell behind.\n");
+        out.push_str("# TYPE quic_speed_access_log_dropped_total counter\n");
+        let _ = writeln!(out, "quic_speed_access_log_dropped_total {}", self.access_log_dropped.get());
+        out
+    }
+}
+
+/// totals of one request, handed to the tracker's finish callback
+#[derive(Debug, Clone, Copy)]
+pub struct RequestSummary {
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+    /// from the request arriving to both bodies being done with
+    pub duration: Duration,
+}
+
+type FinishFn = Box<dyn FnOnce(RequestSummary) + Send>;
+type Guard = Box<dyn std::any::Any + Send + Sync>;
+
+/// byte counts and timing of a single request; shared by its request and
+/// response bodies and finished once the last of them is dropped
+pub struct RequestTracker {
+    metrics: Arc<ServerMetrics>,
+    http_version: HttpVersion,
+    started: Instant,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    on_finish: Mutex<Option<FinishFn>>,
+    guards: Vec<Guard>,
+}
+
+impl RequestTracker {
+    pub fn new(metrics: Arc<ServerMetrics>, http_version: HttpVersion) -> Self {
+        Self {
+            metrics,
+            http_version,
+            started: Instant::now(),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            on_finish: Mutex::new(None),
+            guards: Vec::new(),
+        }
+    }
+
+    /// keep `guard` alive until the request is finished
+    pub fn with_guard<T: Send + Sync + 'static>(mut self, guard: T) -> Self {
+        self.guards.push(Box::new(guard));
+        self
+    }
+
+    /// run `f` with the totals when the request is finished
+    pub fn on_finish<F: FnOnce(RequestSummary) + Send + 'static>(&self, f: F) {
+        *self.on_finish.lock() = Some(Box::new(f));
+    }
+
+    fn add(&self, upload: bool, bytes: u64) {
+        if upload {
+            self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+            self.metrics.uploaded_bytes(self.http_version).add(bytes);
+        } else {
+            self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+            self.metrics.downloaded_bytes(self.http_version).add(bytes);
+        }
+    }
+}
+
+impl Drop for RequestTracker {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed();
+        self.metrics.request_duration(self.http_version).observe(duration);
+        if let Some(f) = self.on_finish.get_mut().take() {
+            f(RequestSummary {
+                uploaded_bytes: *self.uploaded.get_mut(),
+                downloaded_bytes: *self.downloaded.get_mut(),
+                duration,
+            });
+        }
+    }
+}
+
+/// counts the data bytes of a request or response body into its tracker
+pub struct MeteredBody<B> {
+    inner: B,
+    tracker: Arc<RequestTracker>,
+    upload: bool,
+}
+
+impl<B> MeteredBody<B> {
+    /// a request body, counted as uploaded bytes
+    pub fn upload(inner: B, tracker: Arc<RequestTracker>) -> Self {
+        Self { inner, tracker, upload: true }
+    }
+
+    /// a response body, counted as downloaded bytes
+    pub fn download(inner: B, tracker: Arc<RequestTracker>) -> Self {
+        Self { inner, tracker, upload: false }
+    }
+}
+
+impl<B: Body + Unpin> Body for MeteredBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.tracker.add(self.upload, data.remaining() as u64);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
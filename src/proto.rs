@@ -0,0 +1,207 @@
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::io::{
+    Error,
+    ErrorKind,
+};
+use std::time::{Duration, Instant};
+
+use crate::deps;
+use deps::serde_json;
+
+/// width of the intervals in `TransferSampler`
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http1,
+    Http2,
+    Http3,
+}
+
+impl HttpVersion {
+    pub const ALL: [HttpVersion; 3] = [HttpVersion::Http1, HttpVersion::Http2, HttpVersion::Http3];
+
+    /// ALPN identifier used on the wire
+    pub fn alpn(&self) -> &'static [u8] {
+        match self {
+            HttpVersion::Http1 => b"http/1.1",
+            HttpVersion::Http2 => b"h2",
+            HttpVersion::Http3 => b"h3",
+        }
+    }
+}
+
+impl Display for HttpVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpVersion::Http1 => write!(f, "HTTP/1.1"),
+            HttpVersion::Http2 => write!(f, "HTTP/2"),
+            HttpVersion::Http3 => write!(f, "HTTP/3"),
+        }
+    }
+}
+
+/// accepts both the `X-Http-Version` spelling and ALPN-style short names
+impl FromStr for HttpVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "http/1.1" | "http/1.0" | "http/1" | "h1" => Ok(HttpVersion::Http1),
+            "http/2" | "http/2.0" | "h2" => Ok(HttpVersion::Http2),
+            "http/3" | "http/3.0" | "h3" => Ok(HttpVersion::Http3),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown http version: {}", s))),
+        }
+    }
+}
+
+/// parse `10s`, `500ms`, `1.5s` or a bare number of seconds
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let s = s.trim();
+    let (num, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1.0)
+    } else {
+        (s, 1.0)
+    };
+    match num.trim().parse::<f64>() {
+        Ok(n) if n >= 0.0 && n.is_finite() => Ok(Duration::from_secs_f64(n * scale)),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("invalid duration: {}", s))),
+    }
+}
+
+/// bytes moved during one interval; `t` is the end of the interval,
+/// measured from the first byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferSample {
+    pub t: Duration,
+    pub bytes: u64,
+}
+
+impl TransferSample {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "t_ms": self.t.as_secs_f64() * 1000.0,
+            "bytes": self.bytes,
+        })
+    }
+
+    pub fn from_json(v: &serde_json::Value) -> Option<Self> {
+        Some(Self {
+            t: Duration::from_secs_f64(v["t_ms"].as_f64()? / 1000.0),
+            bytes: v["bytes"].as_u64()?,
+        })
+    }
+}
+
+/// counts bytes of a transfer into `SAMPLE_INTERVAL` buckets
+#[derive(Debug, Clone, Default)]
+pub struct TransferSampler {
+    first: Option<Instant>,
+    last: Option<Instant>,
+    bytes: u64,
+    interval_bytes: u64,
+    interval_end: Option<Instant>,
+    samples: Vec<TransferSample>,
+}
+
+impl TransferSampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, now: Instant, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let first = *self.first.get_or_insert(now);
+        let mut interval_end = self.interval_end.unwrap_or(first + SAMPLE_INTERVAL);
+        while now >= interval_end {
+            self.samples.push(TransferSample {
+                t: interval_end - first,
+                bytes: self.interval_bytes,
+            });
+            self.interval_bytes = 0;
+            interval_end += SAMPLE_INTERVAL;
+        }
+        self.interval_end = Some(interval_end);
+        self.interval_bytes += bytes;
+        self.bytes += bytes;
+        self.last = Some(now);
+    }
+
+    /// close the current interval at the last byte
+    pub fn finish(&mut self) {
+        if let (Some(first), Some(last)) = (self.first, self.last) {
+            if self.interval_bytes > 0 {
+                self.samples.push(TransferSample {
+                    t: last - first,
+                    bytes: self.interval_bytes,
+                });
+                self.interval_bytes = 0;
+            }
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn first_byte(&self) -> Option<Instant> {
+        self.first
+    }
+
+    pub fn last_byte(&self) -> Option<Instant> {
+        self.last
+    }
+
+    /// time from first to last byte
+    pub fn elapsed(&self) -> Duration {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => last - first,
+            _ => Duration::ZERO,
+        }
+    }
+
+    pub fn samples(&self) -> &[TransferSample] {
+        &self.samples
+    }
+
+    pub fn samples_json(&self) -> serde_json::Value {
+        serde_json::Value::Array(self.samples.iter().map(|s| s.to_json()).collect())
+    }
+}
+
+/// content of download bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Payload {
+    /// cheapest to serve, but compressible by proxies and VPNs on the path
+    #[default]
+    Zeros,
+    /// pre-generated random blocks that no middlebox can shrink
+    Random,
+}
+
+impl Display for Payload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Payload::Zeros => write!(f, "zeros"),
+            Payload::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl FromStr for Payload {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "zeros" | "zero" => Ok(Payload::Zeros),
+            "random" => Ok(Payload::Random),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown payload: {}", s))),
+        }
+    }
+}
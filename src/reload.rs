@@ -0,0 +1,131 @@
+
+use crate::deps;
+use crate::certs::{
+    CertificateInfo,
+    SelfSignedCertificate,
+};
+use crate::config::Config;
+
+use deps::parking_lot::RwLock;
+use deps::tokio_rustls::TlsAcceptor;
+use deps::quinn;
+use deps::log;
+
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::io::Error;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// what we compare to notice a renewed file; certbot swaps symlinks, so the inode matters
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    #[cfg(unix)]
+    inode: u64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        Some(Self {
+            modified: meta.modified().ok(),
+            len: meta.len(),
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(&meta),
+        })
+    }
+}
+
+fn stamps(config_path: &Path, config: &Config) -> Vec<(PathBuf, Option<FileStamp>)> {
+    let mut files = vec![config_path.to_owned()];
+    files.extend(config.certificate_files());
+    files.into_iter()
+        .map(|path| {
+            let stamp = FileStamp::of(&path);
+            (path, stamp)
+        })
+        .collect()
+}
+
+fn log_certificates(infos: &[(PathBuf, CertificateInfo)]) {
+    for (path, info) in infos {
+        log::info!("Loaded certificate {}: {}", path.display(), info);
+    }
+}
+
+/// rebuilds the TLS and QUIC server configs from the config file and swaps them in place
+#[derive(Clone)]
+pub struct TlsReloader {
+    config_path: PathBuf,
+    tls_acceptor: Arc<RwLock<TlsAcceptor>>,
+    quic_config: Arc<RwLock<quinn::ServerConfig>>,
+    self_signed: Option<SelfSignedCertificate>,
+}
+
+impl TlsReloader {
+    pub fn new(config_path: &Path, tls_acceptor: Arc<RwLock<TlsAcceptor>>, quic_config: Arc<RwLock<quinn::ServerConfig>>) -> Self {
+        Self {
+            config_path: config_path.to_owned(),
+            tls_acceptor,
+            quic_config,
+            self_signed: None,
+        }
+    }
+
+    /// keep serving this certificate across reloads instead of `tls_cert`/`tls_key`
+    pub fn with_self_signed(mut self, self_signed: Option<SelfSignedCertificate>) -> Self {
+        self.self_signed = self_signed;
+        self
+    }
+
+    /// blockingly reload; the current keys stay in place on error
+    pub fn reload(&self) -> Result<Config, Error> {
+        let mut config = Config::load(&self.config_path)?;
+        config.server.self_signed = self.self_signed.clone();
+        let infos = config.check_certificates()?;
+        let tls_acceptor = config.tls_acceptor()?;
+        let quic_config = config.quic_server_config()?;
+        {
+            let mut w = self.tls_acceptor.write();
+            *w = tls_acceptor;
+        }
+        {
+            let mut w = self.quic_config.write();
+            *w = quic_config;
+        }
+        log_certificates(&infos);
+        Ok(config)
+    }
+
+    /// poll the config file and every certificate and key file, reloading when one changes.
+    pub fn watch(self, config: Config, interval: Duration) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut config = config;
+            let mut last = stamps(&self.config_path, &config);
+            loop {
+                std::thread::sleep(interval);
+                let current = stamps(&self.config_path, &config);
+                if current == last {
+                    continue;
+                }
+                log::info!("Certificate files changed, reloading");
+                match self.reload() {
+                    Ok(new_config) => {
+                        config = new_config;
+                        last = stamps(&self.config_path, &config);
+                        log::info!("Config reloaded");
+                    }
+                    Err(e) => {
+                        // wait for the next change; a renewal may be half-written
+                        log::error!("Error reloading config: {:?}", e);
+                        last = current;
+                    }
+                }
+            }
+        })
+    }
+}
@@ -1,6 +1,14 @@
 
 use crate::deps;
 use crate::tcp;
+use crate::udp;
+
+pub use crate::proto::HttpVersion;
+use crate::proto::{self, Payload, TransferSampler};
+use crate::metrics::{MeteredBody, RequestTracker, ServerMetrics};
+use crate::access_log::AccessLog;
+use crate::shutdown::Shutdown;
+use crate::limits::{ClientLimiter, ClientLimits, GlobalLimiter, GlobalLimits, LimitExceeded, ThrottledBody};
 
 use deps::tokio;
 use deps::hyper;
@@ -9,50 +17,279 @@ use deps::log;
 use deps::http_body_util;
 use deps::serde_json;
 use deps::futures;
+use deps::quinn;
+use deps::h3;
+use deps::h3_quinn;
 
 use std::ops::Deref;
 
 use hyper::body::Bytes;
 use hyper::body::Frame;
+use hyper::body::{Body, Buf};
 use hyper::Version;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response};
 use hyper::{Method, StatusCode};
+use hyper::header::{self, HeaderValue};
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use http_body_util::{combinators::BoxBody, BodyExt, Full, Empty};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, Empty, StreamBody};
 use futures::StreamExt;
 use hyper_util::server::conn::auto::Builder;
 
-use deps::parking_lot::RwLock;
+use deps::parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::convert::Infallible;
-use std::fmt::{Display, Formatter};
+use std::task::Poll;
+use tokio::task::JoinSet;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio_rustls::TlsAcceptor;
 
-use std::net::TcpListener;
+use std::net::{
+    SocketAddr,
+    TcpListener,
+    UdpSocket,
+};
 
 static ZEROS: [u8; 65536] = [0u8; 65536];
 
-static INDEX_HTML: &'static str = include_str!("../static/index.html");
+/// size of the random block behind `payload=random`; larger than the window
+/// of the usual deflate, brotli and zstd settings, though a body repeats it
+/// every 4 MiB and a long-window compressor could still find the repeats
+const RANDOM_BLOCK_SIZE: usize = 4 << 20;
+
+/// random bytes served in slices, so random payloads cost no more per byte
+/// than zeros; generated on first use and shared by every clone
+#[derive(Clone, Default)]
+pub struct RandomBlock(Arc<Mutex<Option<Bytes>>>);
 
-#[derive(Debug, Clone, Copy)]
-pub enum HttpVersion {
-    Http1,
-    Http2,
-    Http3,
+impl RandomBlock {
+    fn bytes(&self) -> Result<Bytes, std::io::Error> {
+        let mut block = self.0.lock();
+        if let Some(block) = &*block {
+            return Ok(block.clone());
+        }
+        let mut bytes = vec![0u8; RANDOM_BLOCK_SIZE];
+        deps::ring::rand::SecureRandom::fill(&deps::ring::rand::SystemRandom::new(), &mut bytes)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "failed to generate random payload"))?;
+        let bytes = Bytes::from(bytes);
+        *block = Some(bytes.clone());
+        Ok(bytes)
+    }
 }
 
-impl Display for HttpVersion {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl std::fmt::Debug for RandomBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &*self.0.lock() {
+            Some(block) => write!(f, "RandomBlock({} bytes)", block.len()),
+            None => write!(f, "RandomBlock(not generated)"),
+        }
+    }
+}
+
+/// content of one download body
+#[derive(Debug, Clone)]
+enum PayloadSource {
+    Zeros,
+    Random(Bytes),
+}
+
+impl PayloadSource {
+    /// `len` bytes for the `index`th chunk of a body; random chunks walk the
+    /// block at a stride that is not a power of two
+    fn chunk(&self, index: usize, len: usize) -> Bytes {
         match self {
-            HttpVersion::Http1 => write!(f, "HTTP/1.1"),
-            HttpVersion::Http2 => write!(f, "HTTP/2"),
-            HttpVersion::Http3 => write!(f, "HTTP/3"),
+            PayloadSource::Zeros => Bytes::from_static(&ZEROS[..len]),
+            PayloadSource::Random(block) => {
+                let start = index.wrapping_mul(ZEROS.len() + 4099) % (block.len() - len + 1);
+                block.slice(start..start + len)
+            }
         }
     }
 }
 
+static INDEX_HTML: &str = include_str!("../static/index.html");
+
+/// upper bound for `/download?duration=`
+const MAX_DOWNLOAD_DURATION: Duration = Duration::from_secs(60);
+
+/// longest accepted test-session id
+const MAX_SESSION_ID_LEN: usize = 64;
+
+/// how long a finished timed download stays visible to `/download/status`
+const DOWNLOAD_STATUS_RETENTION: Duration = Duration::from_secs(30);
+
+/// sessions `/download/status` keeps track of at once; downloads of further
+/// sessions are served without progress reports
+const MAX_TRACKED_SESSIONS: usize = 4096;
+
+/// HTTP/3 application error code for a clean close
+const H3_NO_ERROR: u32 = 0x100;
+
+/// longest probe line accepted by `/echo` before the stream is dropped
+const ECHO_MAX_LINE: usize = 4096;
+
+/// knobs shared by every listener, set with `with_options`
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// value of the `Alt-Svc` header on HTTP/1.1 and HTTP/2 responses
+    pub alt_svc: Option<String>,
+    /// download content when the request has no `payload=` parameter
+    pub default_payload: Payload,
+    /// generated on the first `payload=random` download
+    pub random_block: RandomBlock,
+    pub metrics: Arc<ServerMetrics>,
+    /// serve `/metrics` on the test listeners too, not only on a `MetricsServer`
+    pub public_metrics: bool,
+    /// one JSON record per finished request
+    pub access_log: Option<Arc<AccessLog>>,
+    /// per-client request, concurrency and byte limits
+    pub client_limiter: Option<Arc<ClientLimiter>>,
+    /// server-wide connection, test and bandwidth limits
+    pub global_limiter: Arc<GlobalLimiter>,
+    /// largest accepted `/upload` body
+    pub max_upload_bytes: Option<u64>,
+    /// longest accepted upload; timed downloads asking for more end at it
+    pub max_test_duration: Option<Duration>,
+    /// close TCP and TLS connections that have seen no traffic for this long;
+    /// QUIC takes its idle timeout from the transport config of its server config
+    pub idle_timeout: Option<Duration>,
+    /// progress of running timed downloads, reported by `/download/status`
+    pub timed_downloads: Arc<TimedDownloads>,
+}
+
+/// negotiated parameters of a TLS or QUIC connection
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    pub version: Option<String>,
+    pub cipher_suite: Option<String>,
+    /// SNI sent by the client
+    pub server_name: Option<String>,
+    pub alpn: Option<String>,
+}
+
+impl TlsInfo {
+    fn from_rustls(conn: &tokio_rustls::rustls::ServerConnection) -> Self {
+        Self {
+            version: conn.protocol_version().map(|v| v.as_str().map(str::to_owned).unwrap_or_else(|| format!("{:?}", v))),
+            cipher_suite: conn.negotiated_cipher_suite().map(|c| c.suite().as_str().map(str::to_owned).unwrap_or_else(|| format!("{:?}", c.suite()))),
+            server_name: conn.server_name().map(str::to_owned),
+            alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+        }
+    }
+
+    fn from_quic(conn: &quinn::Connection) -> Self {
+        let handshake = conn.handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok());
+        Self {
+            // QUIC always runs TLS 1.3
+            version: Some("TLSv1_3".to_owned()),
+            cipher_suite: None,
+            server_name: handshake.as_ref().and_then(|h| h.server_name.clone()),
+            alpn: handshake.as_ref().and_then(|h| h.protocol.as_ref()).map(|p| String::from_utf8_lossy(p).into_owned()),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "version": self.version,
+            "cipher_suite": self.cipher_suite,
+            "server_name": self.server_name,
+            "alpn": self.alpn,
+        })
+    }
+}
+
+/// what the access log knows about the connection a request arrived on
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer_addr: SocketAddr,
+    /// `None` on the plain listener
+    pub tls: Option<TlsInfo>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            alt_svc: None,
+            default_payload: Payload::default(),
+            random_block: RandomBlock::default(),
+            metrics: Arc::new(ServerMetrics::new()),
+            public_metrics: false,
+            access_log: None,
+            client_limiter: None,
+            global_limiter: Default::default(),
+            max_upload_bytes: None,
+            max_test_duration: None,
+            idle_timeout: None,
+            timed_downloads: Default::default(),
+        }
+    }
+}
+
+impl ServerOptions {
+    /// share a metrics registry with other listeners or a `MetricsServer`
+    pub fn with_metrics(mut self, metrics: Arc<ServerMetrics>, public: bool) -> Self {
+        self.metrics = metrics;
+        self.public_metrics = public;
+        self
+    }
+
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// refuse requests beyond `limits` with 429; unlimited limits disable the limiter
+    pub fn with_client_limits(mut self, limits: ClientLimits) -> Self {
+        self.client_limiter = if limits.is_unlimited() {
+            None
+        } else {
+            Some(Arc::new(ClientLimiter::new(limits)))
+        };
+        self
+    }
+
+    pub fn with_global_limits(mut self, limits: GlobalLimits) -> Self {
+        self.global_limiter = Arc::new(GlobalLimiter::new(limits));
+        self
+    }
+
+    pub fn with_max_upload_bytes(mut self, max: u64) -> Self {
+        self.max_upload_bytes = Some(max);
+        self
+    }
+
+    pub fn with_max_test_duration(mut self, max: Duration) -> Self {
+        self.max_test_duration = Some(max);
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_default_payload(mut self, payload: Payload) -> Self {
+        self.default_payload = payload;
+        self
+    }
+
+    fn payload_source(&self, payload: Payload) -> Result<PayloadSource, std::io::Error> {
+        match payload {
+            Payload::Zeros => Ok(PayloadSource::Zeros),
+            Payload::Random => self.random_block.bytes().map(PayloadSource::Random),
+        }
+    }
+
+    /// advertise an HTTP/3 endpoint on `port` for `max_age` seconds
+    pub fn with_h3_alt_svc(mut self, port: u16, max_age: u32) -> Self {
+        self.alt_svc = Some(format!("h3=\":{}\"; ma={}", port, max_age));
+        self
+    }
+}
+
 #[allow(dead_code)]
 fn empty() -> BoxBody<Bytes, Infallible> {
     Empty::<Bytes>::new()
@@ -74,7 +311,424 @@ fn json_response(status: StatusCode, http_version: HttpVersion, v: serde_json::V
         .unwrap()
 }
 
-async fn handle_request(req: Request<hyper::body::Incoming>, http_version: HttpVersion) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+/// wall clock in milliseconds since the unix epoch, as reported to clients
+fn unix_time_ms() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64() * 1000.0)
+        .unwrap_or(0.0)
+}
+
+/// value of `key` in an `a=1&b=2` query string
+fn query_param<'a>(query: Option<&'a str>, key: &str) -> Option<&'a str> {
+    query?.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (k == key).then_some(v)
+    })
+}
+
+fn metrics_response(metrics: &ServerMetrics) -> Response<BoxBody<Bytes, Infallible>> {
+    let mut res = Response::new(full(metrics.render()));
+    res.headers_mut().insert("Content-Type", "text/plain; version=0.0.4".parse().unwrap());
+    res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
+    res
+}
+
+/// random id handed out by `POST /session`
+fn new_session_id() -> Result<String, std::io::Error> {
+    let mut id = [0u8; 16];
+    deps::ring::rand::SecureRandom::fill(&deps::ring::rand::SystemRandom::new(), &mut id)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "failed to generate session id"))?;
+    Ok(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// test-session id from the `X-Test-Session` header or the `session=` parameter,
+/// so that the parallel flows of one test can be told apart in logs
+fn session_id<B>(req: &Request<B>) -> Option<String> {
+    let id = req.headers().get("x-test-session")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| query_param(req.uri().query(), "session"))?;
+    let valid = !id.is_empty()
+        && id.len() <= MAX_SESSION_ID_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    valid.then(|| id.to_owned())
+}
+
+/// answer a latency probe; `t` and `seq` from the query are echoed back
+/// next to the server clock so clients can cancel out clock skew
+fn ping_response(query: Option<&str>, http_version: HttpVersion) -> Response<BoxBody<Bytes, Infallible>> {
+    let mut v = serde_json::json!({
+        "server_time_ms": unix_time_ms(),
+    });
+    if let Some(t) = query_param(query, "t").and_then(|t| t.parse::<f64>().ok()) {
+        v["client_time_ms"] = t.into();
+    }
+    if let Some(seq) = query_param(query, "seq").and_then(|seq| seq.parse::<u64>().ok()) {
+        v["seq"] = seq.into();
+    }
+    let mut res = json_response(StatusCode::OK, http_version, v);
+    res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
+    res
+}
+
+/// long-lived echo stream: every newline-terminated JSON object in the
+/// request body is written back as soon as it arrives, stamped with the
+/// server receive and send times
+fn echo_response<B>(body: B, http_version: HttpVersion) -> Response<BoxBody<Bytes, Infallible>>
+where
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: std::fmt::Debug + Send,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Bytes>(64);
+    tokio::task::spawn(async move {
+        let mut body = body;
+        let mut buf: Vec<u8> = Vec::new();
+        while let Some(frame) = body.frame().await {
+            let mut data = match frame {
+                Ok(frame) => match frame.into_data() {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                },
+                Err(e) => {
+                    log::debug!("echo frame error: {:?}", e);
+                    return;
+                }
+            };
+            let received = unix_time_ms();
+            while data.has_remaining() {
+                let chunk = data.chunk();
+                let len = chunk.len();
+                buf.extend_from_slice(chunk);
+                data.advance(len);
+            }
+            while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buf.drain(..=pos).collect();
+                let mut reply = match serde_json::from_slice::<serde_json::Value>(&line) {
+                    Ok(v) if v.is_object() => v,
+                    _ => serde_json::json!({ "error": "invalid probe" }),
+                };
+                reply["server_received_ms"] = received.into();
+                reply["server_sent_ms"] = unix_time_ms().into();
+                let mut out = serde_json::to_vec(&reply).unwrap();
+                out.push(b'\n');
+                if tx.send(Bytes::from(out)).await.is_err() {
+                    return;
+                }
+            }
+            if buf.len() > ECHO_MAX_LINE {
+                log::debug!("echo probe too long, closing stream");
+                return;
+            }
+        }
+    });
+
+    let body = futures::stream::poll_fn(move |cx| {
+        rx.poll_recv(cx).map(|data| data.map(|data| Ok::<_, Infallible>(Frame::data(data))))
+    });
+    let mut res = Response::new(BoxBody::new(StreamBody::new(body)));
+    res.headers_mut().insert("Content-Type", "application/x-ndjson".parse().unwrap());
+    res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
+    res.headers_mut().insert("X-Http-Version", http_version.to_string().parse().unwrap());
+    res
+}
+
+/// server-side counters of one timed download
+#[derive(Debug)]
+struct DownloadProgress {
+    started: Instant,
+    finished: Option<Instant>,
+    sampler: TransferSampler,
+}
+
+impl DownloadProgress {
+    fn to_json(&self, now: Instant) -> serde_json::Value {
+        let elapsed = self.finished.unwrap_or(now) - self.started;
+        serde_json::json!({
+            "transferred_bytes": self.sampler.bytes(),
+            "elapsed_ms": elapsed.as_secs_f64() * 1000.0,
+            "finished": self.finished.is_some(),
+            "samples": self.sampler.samples_json(),
+        })
+    }
+}
+
+/// marks the download finished however its body ends, including a client
+/// going away before the deadline
+struct DownloadProgressGuard(Arc<Mutex<DownloadProgress>>);
+
+impl Drop for DownloadProgressGuard {
+    fn drop(&mut self) {
+        let mut progress = self.0.lock();
+        if progress.finished.is_none() {
+            progress.finished = Some(Instant::now());
+            progress.sampler.finish();
+        }
+    }
+}
+
+/// timed downloads by test session, so that clients can poll the server-side
+/// byte counters with `GET /download/status?session=` while the body streams
+#[derive(Debug, Default)]
+pub struct TimedDownloads {
+    sessions: Mutex<HashMap<String, Vec<Arc<Mutex<DownloadProgress>>>>>,
+}
+
+impl TimedDownloads {
+    /// track a new download of `session`; `None` when too many sessions are tracked
+    fn register(&self, session: &str) -> Option<Arc<Mutex<DownloadProgress>>> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock();
+        sessions.retain(|_, downloads| downloads.iter().any(|download| {
+            download.lock().finished.map_or(true, |finished| now - finished < DOWNLOAD_STATUS_RETENTION)
+        }));
+        if !sessions.contains_key(session) && sessions.len() >= MAX_TRACKED_SESSIONS {
+            return None;
+        }
+        let progress = Arc::new(Mutex::new(DownloadProgress {
+            started: now,
+            finished: None,
+            sampler: TransferSampler::new(),
+        }));
+        sessions.entry(session.to_owned()).or_default().push(progress.clone());
+        Some(progress)
+    }
+
+    /// totals over the downloads of `session` followed by each of them
+    fn status_json(&self, session: &str) -> Option<serde_json::Value> {
+        let now = Instant::now();
+        let sessions = self.sessions.lock();
+        let downloads: Vec<_> = sessions.get(session)?.iter().map(|download| download.lock()).collect();
+        let started = downloads.iter().map(|d| d.started).min()?;
+        let active = downloads.iter().filter(|d| d.finished.is_none()).count();
+        let end = if active > 0 { now } else { downloads.iter().filter_map(|d| d.finished).max().unwrap_or(now) };
+        Some(serde_json::json!({
+            "session": session,
+            "active": active,
+            "transferred_bytes": downloads.iter().map(|d| d.sampler.bytes()).sum::<u64>(),
+            "elapsed_ms": (end - started).as_secs_f64() * 1000.0,
+            "downloads": downloads.iter().map(|d| d.to_json(now)).collect::<Vec<_>>(),
+        }))
+    }
+}
+
+/// `GET /download/status?session=`: byte counters of the session's timed downloads
+fn download_status_response(session: Option<&str>, http_version: HttpVersion, options: &ServerOptions) -> Response<BoxBody<Bytes, Infallible>> {
+    let mut res = match session {
+        None => json_response(StatusCode::BAD_REQUEST, http_version, serde_json::json!({
+            "error": "missing session"
+        })),
+        Some(session) => match options.timed_downloads.status_json(session) {
+            Some(status) => json_response(StatusCode::OK, http_version, status),
+            None => json_response(StatusCode::NOT_FOUND, http_version, serde_json::json!({
+                "error": "no timed downloads for this session"
+            })),
+        },
+    };
+    res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
+    res
+}
+
+/// stream `payload` until `duration` has passed; with a session, progress is
+/// reported by `/download/status` while the body streams, and the final
+/// counters also follow in the trailers for clients that read them
+fn timed_download_response(duration: Duration, source: PayloadSource, session: Option<&str>, http_version: HttpVersion, options: Arc<ServerOptions>) -> Response<BoxBody<Bytes, Infallible>> {
+    let start = Instant::now();
+    let mut index = 0;
+    let deadline = start + duration;
+    let progress = session.and_then(|session| options.timed_downloads.register(session))
+        .unwrap_or_else(|| Arc::new(Mutex::new(DownloadProgress {
+            started: start,
+            finished: None,
+            sampler: TransferSampler::new(),
+        })));
+    let progress = DownloadProgressGuard(progress);
+    // a chunk is counted once the connection asks for the next one, that is
+    // when it has been written rather than when it was produced
+    let mut unsent = 0;
+    let mut finished = false;
+    let body = futures::stream::poll_fn(move |_| {
+        if finished {
+            return Poll::Ready(None);
+        }
+        let now = Instant::now();
+        let mut progress = progress.0.lock();
+        progress.sampler.add(now, unsent);
+        unsent = 0;
+        if now >= deadline {
+            finished = true;
+            progress.finished = Some(now);
+            progress.sampler.finish();
+            let sampler = &progress.sampler;
+            log::debug!("timed download: {} bytes in {:?}", sampler.bytes(), now - start);
+            let mut trailers = header::HeaderMap::new();
+            trailers.insert("x-transferred-bytes", sampler.bytes().into());
+            trailers.insert("x-elapsed-ms", HeaderValue::from_str(&format!("{:.3}", (now - start).as_secs_f64() * 1000.0)).unwrap());
+            trailers.insert("x-samples", HeaderValue::from_str(&sampler.samples_json().to_string()).unwrap());
+            return Poll::Ready(Some(Ok::<_, Infallible>(Frame::trailers(trailers))));
+        }
+        let chunk = source.chunk(index, ZEROS.len());
+        index += 1;
+        unsent = chunk.len() as u64;
+        Poll::Ready(Some(Ok(Frame::data(chunk))))
+    });
+    let mut res = Response::new(BoxBody::new(StreamBody::new(body)));
+    res.headers_mut().insert("Content-Type", "application/octet-stream".parse().unwrap());
+    res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
+    res.headers_mut().insert("Trailer", "x-transferred-bytes, x-elapsed-ms, x-samples".parse().unwrap());
+    res.headers_mut().insert("X-Http-Version", http_version.to_string().parse().unwrap());
+    res
+}
+
+/// requests that move test data, as opposed to probes and page loads
+fn is_test<B>(req: &Request<B>) -> bool {
+    let path = req.uri().path();
+    match *req.method() {
+        Method::GET => path == "/download" || (path.starts_with("/download/") && path != "/download/status"),
+        Method::POST => path == "/upload" || path == "/echo",
+        _ => false,
+    }
+}
+
+/// body size of a test when known before it starts
+fn expected_test_bytes<B>(req: &Request<B>) -> Option<u64> {
+    match *req.method() {
+        Method::GET => req.uri().path().strip_prefix("/download/").and_then(|len| len.parse().ok()),
+        Method::POST => req.headers().get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+        _ => None,
+    }
+}
+
+fn internal_error_response(e: std::io::Error, http_version: HttpVersion) -> Response<BoxBody<Bytes, Infallible>> {
+    log::error!("{}", e);
+    json_response(StatusCode::INTERNAL_SERVER_ERROR, http_version, serde_json::json!({
+        "error": e.to_string()
+    }))
+}
+
+fn limit_response(e: LimitExceeded, http_version: HttpVersion) -> Response<BoxBody<Bytes, Infallible>> {
+    let status = match e {
+        LimitExceeded::ServerBusy => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::TOO_MANY_REQUESTS,
+    };
+    let mut res = json_response(status, http_version, serde_json::json!({
+        "error": e.to_string(),
+        "retry_after_s": e.retry_after_secs(),
+    }));
+    res.headers_mut().insert(header::RETRY_AFTER, e.retry_after_secs().into());
+    res
+}
+
+async fn handle_request<B>(req: Request<B>, http_version: HttpVersion, conn: Arc<ConnectionInfo>, options: Arc<ServerOptions>) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible>
+where
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: std::fmt::Debug + Send,
+{
+    let started_ms = unix_time_ms();
+    let session = session_id(&req);
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let mut tracker = RequestTracker::new(options.metrics.clone(), http_version);
+    let test = is_test(&req);
+    let mut limited = None;
+    let mut client_permit = None;
+    if let Some(limiter) = &options.client_limiter {
+        match limiter.admit(conn.peer_addr.ip(), test, expected_test_bytes(&req)) {
+            Ok(permit) => client_permit = Some(Arc::new(permit)),
+            Err(e) => limited = Some(e),
+        }
+    }
+    if limited.is_none() && test {
+        match options.global_limiter.start_test() {
+            Ok(Some(permit)) => tracker = tracker.with_guard(permit),
+            Ok(None) => {}
+            Err(e) => limited = Some(e),
+        }
+    }
+    // only admitted requests hold the client permit, so a refused test does
+    // not count against the client's concurrent tests
+    let mut byte_budget = None;
+    if let (None, Some(permit)) = (&limited, client_permit) {
+        if test {
+            byte_budget = permit.byte_budget();
+        }
+        tracker = tracker.with_guard(permit);
+    }
+    // probes and error responses are not held back by the bandwidth limits
+    let (ingress, egress) = match (&limited, test) {
+        (None, true) => (options.global_limiter.ingress(), options.global_limiter.egress()),
+        _ => (None, None),
+    };
+    let tracker = Arc::new(tracker);
+    let req = req.map(|body| {
+        ThrottledBody::new(ThrottledBody::new(MeteredBody::upload(body, tracker.clone()), byte_budget.clone()), ingress)
+    });
+    let res = match limited {
+        Some(e) => limit_response(e, http_version),
+        None => route_request(req, http_version, options.clone()).await?,
+    };
+    if let Some(access_log) = options.access_log.clone() {
+        let metrics = options.metrics.clone();
+        let mut record = serde_json::json!({
+            "time_ms": started_ms,
+            "peer": conn.peer_addr.to_string(),
+            "http_version": http_version.to_string(),
+            "alpn": conn.tls.as_ref().and_then(|tls| tls.alpn.clone()),
+            "method": method.as_str(),
+            "route": path,
+            "status": res.status().as_u16(),
+            "session": session,
+            "tls": conn.tls.as_ref().map(TlsInfo::to_json),
+        });
+        tracker.on_finish(move |summary| {
+            let secs = summary.duration.as_secs_f64();
+            let bytes = summary.uploaded_bytes + summary.downloaded_bytes;
+            record["uploaded_bytes"] = summary.uploaded_bytes.into();
+            record["downloaded_bytes"] = summary.downloaded_bytes.into();
+            record["duration_ms"] = (secs * 1000.0).into();
+            record["bits_per_second"] = if secs > 0.0 { (bytes as f64 * 8.0 / secs).into() } else { serde_json::Value::Null };
+            if !access_log.write(record) {
+                metrics.access_log_dropped.add(1);
+            }
+        });
+    }
+    let mut res = res.map(|body| {
+        BoxBody::new(ThrottledBody::new(ThrottledBody::new(MeteredBody::download(body, tracker), byte_budget), egress))
+    });
+    if let Some(session) = session {
+        log::debug!("session {}: {} {} {} -> {}", session, http_version, method, path, res.status().as_u16());
+        if let Ok(value) = HeaderValue::from_str(&session) {
+            res.headers_mut().insert("X-Test-Session", value);
+        }
+    }
+    if http_version != HttpVersion::Http3 {
+        if let Some(alt_svc) = options.alt_svc.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            res.headers_mut().insert(header::ALT_SVC, alt_svc);
+        }
+    }
+    Ok(res)
+}
+
+async fn route_request<B>(req: Request<B>, http_version: HttpVersion, options: Arc<ServerOptions>) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible>
+where
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: std::fmt::Debug + Send,
+{
+    let path = req.uri().path();
+    if req.method() == Method::GET && (path == "/download" || path.starts_with("/download/")) {
+        if let Some(Err(_)) = query_param(req.uri().query(), "payload").map(|p| p.parse::<Payload>()) {
+            return Ok(json_response(StatusCode::BAD_REQUEST, http_version, serde_json::json!({
+                "error": "invalid payload"
+            })));
+        }
+    }
+    let payload = query_param(req.uri().query(), "payload")
+        .and_then(|p| p.parse::<Payload>().ok())
+        .unwrap_or(options.default_payload);
     let res = match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => {
             let mut res = Response::new(full(INDEX_HTML));
@@ -83,13 +737,45 @@ async fn handle_request(req: Request<hyper::body::Incoming>, http_version: HttpV
             res
         }
         (&Method::POST, "/upload") => {
+            let received = Instant::now();
+            let too_large = |uploaded: u64| json_response(StatusCode::PAYLOAD_TOO_LARGE, http_version, serde_json::json!({
+                "error": "upload too large",
+                "max_upload_bytes": options.max_upload_bytes,
+                "uploaded_bytes": uploaded,
+            }));
+            if let (Some(len), Some(max)) = (expected_test_bytes(&req), options.max_upload_bytes) {
+                if len > max {
+                    return Ok(too_large(0));
+                }
+            }
+            let deadline = options.max_test_duration.map(|max| tokio::time::Instant::from(received + max));
             let mut body = req.into_body();
-            let mut bytes: usize = 0;
-            while let Some(frame) = body.frame().await {
+            let mut sampler = TransferSampler::new();
+            loop {
+                let frame = match deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, body.frame()).await {
+                        Ok(frame) => frame,
+                        Err(_) => {
+                            return Ok(json_response(StatusCode::REQUEST_TIMEOUT, http_version, serde_json::json!({
+                                "error": "test duration exceeded",
+                                "max_test_duration_ms": options.max_test_duration.map(|d| d.as_millis() as u64),
+                                "uploaded_bytes": sampler.bytes(),
+                            })));
+                        }
+                    },
+                    None => body.frame().await,
+                };
+                let frame = match frame {
+                    Some(frame) => frame,
+                    None => break,
+                };
                 match frame {
                     Ok(frame) => {
                         if let Ok(data) = frame.into_data() {
-                            bytes += data.len();
+                            sampler.add(Instant::now(), data.remaining() as u64);
+                            if options.max_upload_bytes.map_or(false, |max| sampler.bytes() > max) {
+                                return Ok(too_large(sampler.bytes()));
+                            }
                         };
                     }
                     Err(e) => {
@@ -100,38 +786,86 @@ async fn handle_request(req: Request<hyper::body::Incoming>, http_version: HttpV
                     }
                 }
             }
+            sampler.finish();
 
+            let since_received = |t: Option<Instant>| t.map(|t| (t - received).as_secs_f64() * 1000.0);
+            let elapsed = sampler.elapsed().as_secs_f64();
+            let bits_per_second = if elapsed > 0.0 { (sampler.bytes() as f64 * 8.0 / elapsed) as u64 } else { 0 };
             json_response(StatusCode::OK, http_version, serde_json::json!({
-                "uploaded_bytes": bytes
+                "uploaded_bytes": sampler.bytes(),
+                "first_byte_ms": since_received(sampler.first_byte()),
+                "last_byte_ms": since_received(sampler.last_byte()),
+                "elapsed_ms": elapsed * 1000.0,
+                "bits_per_second": bits_per_second,
+                "samples": sampler.samples_json(),
             }))
         }
+        (&Method::GET, "/metrics") if options.public_metrics => metrics_response(&options.metrics),
+        (&Method::POST, "/session") => match new_session_id() {
+            Ok(session_id) => {
+                let mut res = json_response(StatusCode::OK, http_version, serde_json::json!({
+                    "session_id": session_id
+                }));
+                res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
+                res
+            }
+            Err(e) => internal_error_response(e, http_version),
+        },
+        (&Method::GET, "/ping") => ping_response(req.uri().query(), http_version),
+        (&Method::POST, "/echo") => echo_response(req.into_body(), http_version),
+        (&Method::GET, "/download/status") => download_status_response(session_id(&req).as_deref(), http_version, &options),
+        (&Method::GET, "/download") => {
+            match query_param(req.uri().query(), "duration").map(proto::parse_duration) {
+                Some(Ok(duration)) if duration <= MAX_DOWNLOAD_DURATION => {
+                    // cut short at the test duration cap rather than refused
+                    let duration = options.max_test_duration.map_or(duration, |max| duration.min(max));
+                    match options.payload_source(payload) {
+                        Ok(source) => timed_download_response(duration, source, session_id(&req).as_deref(), http_version, options),
+                        Err(e) => internal_error_response(e, http_version),
+                    }
+                }
+                // tells clients the longest duration to retry with
+                Some(Ok(_)) => json_response(StatusCode::BAD_REQUEST, http_version, serde_json::json!({
+                    "error": "duration too long",
+                    "max_duration_ms": MAX_DOWNLOAD_DURATION.as_millis() as u64,
+                })),
+                _ => json_response(StatusCode::BAD_REQUEST, http_version, serde_json::json!({
+                    "error": "invalid duration"
+                })),
+            }
+        }
         (&Method::GET, uri) => {
-            let download_prefix = "/download/";
-            if uri.starts_with(download_prefix) {
-                let len = &uri[download_prefix.len()..];
+            if let Some(len) = uri.strip_prefix("/download/") {
                 let len = len.parse::<usize>().unwrap_or(0);
                 if len > 1073741824 {
                     json_response(StatusCode::BAD_REQUEST, http_version, serde_json::json!({
                         "error": "too large"
                     }))
                 } else {
-                    let mut remaining = len;
-                    let body = futures::stream::repeat_with(move || {
-                        let chunk = std::cmp::min(remaining, 65536);
-                        remaining -= chunk;
-                        if chunk > 0 {
-                            Some(Bytes::from(&ZEROS[..chunk]))
-                        } else {
-                            None
+                    match options.payload_source(payload) {
+                        Err(e) => internal_error_response(e, http_version),
+                        Ok(source) => {
+                            let mut remaining = len;
+                            let mut index = 0;
+                            let body = futures::stream::repeat_with(move || {
+                                let chunk = std::cmp::min(remaining, 65536);
+                                remaining -= chunk;
+                                index += 1;
+                                if chunk > 0 {
+                                    Some(source.chunk(index - 1, chunk))
+                                } else {
+                                    None
+                                }
+                            }).take_while(|x| futures::future::ready(x.is_some())).map(|x| Ok(Frame::data(x.unwrap())));
+                            let mut res = Response::new(BoxBody::new(http_body_util::StreamBody::new(body)));
+                            res.headers_mut().insert("Content-Type", "application/octet-stream".parse().unwrap());
+                            res.headers_mut().insert("Content-Length", len.to_string().parse().unwrap());
+                            res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
+                            res.headers_mut().insert("X-Http-Version", http_version.to_string().parse().unwrap());
+                            *res.status_mut() = StatusCode::OK;
+                            res
                         }
-                    }).take_while(|x| futures::future::ready(x.is_some())).map(|x| Ok(Frame::data(x.unwrap())));
-                    let mut res = Response::new(BoxBody::new(http_body_util::StreamBody::new(body)));
-                    res.headers_mut().insert("Content-Type", "application/octet-stream".parse().unwrap());
-                    res.headers_mut().insert("Content-Length", len.to_string().parse().unwrap());
-                    res.headers_mut().insert("Cache-Control", "no-store".parse().unwrap());
-                    res.headers_mut().insert("X-Http-Version", http_version.to_string().parse().unwrap());
-                    *res.status_mut() = StatusCode::OK;
-                    res
+                    }
                 }
             } else {
                 json_response(StatusCode::NOT_FOUND, http_version, serde_json::json!({
@@ -150,49 +884,107 @@ async fn handle_request(req: Request<hyper::body::Incoming>, http_version: HttpV
 
 pub struct PlainHttpServer {
     listener: TcpListener,
+    options: Arc<ServerOptions>,
+    shutdown: Shutdown,
 }
 
 impl PlainHttpServer {
     pub fn new_from_listener(listener: TcpListener) -> Self {
-        Self { listener }
+        Self { listener, options: Default::default(), shutdown: Default::default() }
     }
 
     pub fn new(port: u16, bind_device: Option<&[u8]>) -> Result<Self, std::io::Error> {
         let listener = tcp::listen(port, None, bind_device)?;
-        Ok(Self { listener })
+        Ok(Self::new_from_listener(listener))
+    }
+
+    pub fn new_with_addr(addr: SocketAddr, bind_device: Option<&[u8]>) -> Result<Self, std::io::Error> {
+        let listener = tcp::listen_addr(addr, None, bind_device)?;
+        Ok(Self::new_from_listener(listener))
+    }
+
+    pub fn with_options(mut self, options: Arc<ServerOptions>) -> Self {
+        self.options = options;
+        self
     }
 
-    async fn run(&self) {
-        let listener = tokio::net::TcpListener::from_std(self.listener.try_clone().unwrap()).unwrap();
+    /// shutdown watched by `start`; `serve` takes its own
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// accept and serve connections on the current Tokio runtime until
+    /// `shutdown` is triggered and the open connections are drained
+    pub async fn serve(self, shutdown: Shutdown) -> Result<(), std::io::Error> {
+        self.listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(self.listener)?;
+        let local_addr = listener.local_addr()?;
+        let metrics = self.options.metrics.listener("plain", local_addr);
+        let slots = self.options.global_limiter.connection_slots();
+        let mut connections = JoinSet::new();
         loop {
-            let stream = if let Ok((stream, _)) = listener.accept().await {
-                stream
-            } else {
-                continue;
+            // at the connection limit, new connections wait in the backlog
+            let (stream, peer_addr, slot) = tokio::select! {
+                accepted = async {
+                    let slot = slots.acquire().await;
+                    listener.accept().await.map(|(stream, peer_addr)| (stream, peer_addr, slot))
+                } => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => continue,
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = shutdown.triggered() => break,
             };
 
-            let io = TokioIo::new(stream);
-            tokio::task::spawn(async move {
+            let io = TokioIo::new(tcp::IdleTimeout::new(stream, self.options.idle_timeout));
+            let conn_info = Arc::new(ConnectionInfo { peer_addr, tls: None });
+            let options = self.options.clone();
+            let shutdown = shutdown.clone();
+            let active = metrics.connection();
+            connections.spawn(async move {
+                let _active = active;
+                let _slot = slot;
                 let service = service_fn(|req: _| {
                     let http_version = HttpVersion::Http1;
-                    handle_request(req, http_version)
+                    handle_request(req, http_version, conn_info.clone(), options.clone())
                 });
                 let conn = http1::Builder::new().serve_connection(io, service);
-                if let Err(e) = conn.await {
+                tokio::pin!(conn);
+                let result = tokio::select! {
+                    result = conn.as_mut() => result,
+                    _ = shutdown.triggered() => {
+                        // lets the response in flight finish, then closes
+                        conn.as_mut().graceful_shutdown();
+                        tokio::select! {
+                            result = conn.as_mut() => result,
+                            _ = shutdown.deadline() => Ok(()),
+                        }
+                    }
+                };
+                if let Err(e) = result {
                     log::error!("http1 connection error: {:?}", e);
                 }
             });
         }
+
+        drop(listener);
+        log::info!("plain listener {} closed, draining {} connections", local_addr, connections.len());
+        shutdown.drain(&mut connections).await;
+        Ok(())
     }
 
-    /// start the server in background.
+    /// start the server in background, on a thread with its own runtime.
     pub fn start(self) -> std::thread::JoinHandle<()> {
         std::thread::spawn(move || {
             let rt = tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
                 .build()
                 .unwrap();
-            rt.block_on(self.run());
+            let shutdown = self.shutdown.clone();
+            if let Err(e) = rt.block_on(self.serve(shutdown)) {
+                log::error!("plain http server failed: {}", e);
+            }
         })
     }
 }
@@ -200,64 +992,660 @@ impl PlainHttpServer {
 pub struct TlsHttpServer {
     listener: TcpListener,
     tls_acceptor: Arc<RwLock<TlsAcceptor>>,
+    options: Arc<ServerOptions>,
+    shutdown: Shutdown,
 }
 
 impl TlsHttpServer {
     pub fn new(acceptor: Arc<RwLock<TlsAcceptor>>, port: u16, bind_device: Option<&[u8]>) -> Result<Self, std::io::Error> {
         let listener = tcp::listen(port, None, bind_device)?;
-        Ok(Self { listener, tls_acceptor: acceptor })
+        Ok(Self { listener, tls_acceptor: acceptor, options: Default::default(), shutdown: Default::default() })
+    }
+
+    pub fn new_with_addr(acceptor: Arc<RwLock<TlsAcceptor>>, addr: SocketAddr, bind_device: Option<&[u8]>) -> Result<Self, std::io::Error> {
+        let listener = tcp::listen_addr(addr, None, bind_device)?;
+        Ok(Self { listener, tls_acceptor: acceptor, options: Default::default(), shutdown: Default::default() })
+    }
+
+    pub fn with_options(mut self, options: Arc<ServerOptions>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// shutdown watched by `start`; `serve` takes its own
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
     }
 
 
-    async fn run(&self) {
-        let listener = tokio::net::TcpListener::from_std(self.listener.try_clone().unwrap()).unwrap();
+    /// accept and serve connections on the current Tokio runtime until
+    /// `shutdown` is triggered and the open connections are drained
+    pub async fn serve(self, shutdown: Shutdown) -> Result<(), std::io::Error> {
+        self.listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(self.listener)?;
+        let local_addr = listener.local_addr()?;
+        let metrics = self.options.metrics.listener("tls", local_addr);
+        let slots = self.options.global_limiter.connection_slots();
+        let mut connections = JoinSet::new();
         loop {
-            let stream = if let Ok((stream, _)) = listener.accept().await {
-                stream
-            } else {
-                continue;
+            // at the connection limit, new connections wait in the backlog
+            let (stream, peer_addr, slot) = tokio::select! {
+                accepted = async {
+                    let slot = slots.acquire().await;
+                    listener.accept().await.map(|(stream, peer_addr)| (stream, peer_addr, slot))
+                } => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => continue,
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = shutdown.triggered() => break,
             };
 
             let acceptor = self.tls_acceptor.clone();
-            tokio::task::spawn(async move {
+            let options = self.options.clone();
+            let shutdown = shutdown.clone();
+            let metrics = metrics.clone();
+            let active = metrics.connection();
+            connections.spawn(async move {
+                let _active = active;
+                let _slot = slot;
                 let tls_acceptor = {
                     let read = acceptor.read();
                     let acceptor = read.deref().clone();
                     acceptor
                 };
                 
+                // covers the handshake too, so stalled handshakes are dropped as well
+                let stream = tcp::IdleTimeout::new(stream, options.idle_timeout);
                 let tls_stream = match tls_acceptor.accept(stream).await {
                     Ok(tls_stream) => tls_stream,
                     Err(err) => {
+                        metrics.handshake_failures.add(1);
                         log::error!("failed to perform tls handshake: {err:#}");
                         return;
                     }
                 };
+                let conn_info = Arc::new(ConnectionInfo {
+                    peer_addr,
+                    tls: Some(TlsInfo::from_rustls(tls_stream.get_ref().1)),
+                });
                 let service = service_fn(|req: _| {
                     let http_version = match req.version() {
                         Version::HTTP_2 => HttpVersion::Http2,
                         _ => HttpVersion::Http1
                     };
-                    handle_request(req, http_version)
+                    handle_request(req, http_version, conn_info.clone(), options.clone())
                 });
-                if let Err(err) = Builder::new(TokioExecutor::new())
-                    .serve_connection(TokioIo::new(tls_stream), service)
-                    .await
-                {
+                let builder = Builder::new(TokioExecutor::new());
+                let conn = builder.serve_connection(TokioIo::new(tls_stream), service);
+                tokio::pin!(conn);
+                let result = tokio::select! {
+                    result = conn.as_mut() => result,
+                    _ = shutdown.triggered() => {
+                        // HTTP/2 gets a GOAWAY; streams in flight may finish
+                        conn.as_mut().graceful_shutdown();
+                        tokio::select! {
+                            result = conn.as_mut() => result,
+                            _ = shutdown.deadline() => Ok(()),
+                        }
+                    }
+                };
+                if let Err(err) = result {
                     log::error!("failed to serve connection: {err:#}");
                 }
             });
         }
+
+        drop(listener);
+        log::info!("tls listener {} closed, draining {} connections", local_addr, connections.len());
+        shutdown.drain(&mut connections).await;
+        Ok(())
+    }
+
+    /// start the server in background, on a thread with its own runtime.
+    pub fn start(self) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let shutdown = self.shutdown.clone();
+            if let Err(e) = rt.block_on(self.serve(shutdown)) {
+                log::error!("tls http server failed: {}", e);
+            }
+        })
+    }
+}
+
+/// feed an HTTP/3 request through `handle_request` and write back the response
+async fn serve_h3_request<S>(req: Request<()>, stream: h3::server::RequestStream<S, Bytes>, conn: Arc<ConnectionInfo>, options: Arc<ServerOptions>) -> Result<(), h3::error::StreamError>
+where
+    S: h3::quic::BidiStream<Bytes> + Send + 'static,
+    S::RecvStream: Send + 'static,
+{
+    let (mut send, recv) = stream.split();
+    let body = futures::stream::unfold(recv, |mut recv| async move {
+        match recv.recv_data().await {
+            Ok(Some(mut data)) => {
+                let data = data.copy_to_bytes(data.remaining());
+                Some((Ok(Frame::data(data)), recv))
+            }
+            Ok(None) => None,
+            Err(e) => Some((Err(e), recv)),
+        }
+    });
+    let req = req.map(|_| StreamBody::new(Box::pin(body)));
+
+    let res = match handle_request(req, HttpVersion::Http3, conn, options).await {
+        Ok(res) => res,
+        Err(e) => match e {},
+    };
+    let (parts, mut body) = res.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => match e {},
+        };
+        match frame.into_data() {
+            Ok(data) => send.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    send.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+    send.finish().await
+}
+
+pub struct Http3Server {
+    socket: UdpSocket,
+    server_config: Arc<RwLock<quinn::ServerConfig>>,
+    options: Arc<ServerOptions>,
+    shutdown: Shutdown,
+}
+
+impl Http3Server {
+    pub fn new(server_config: Arc<RwLock<quinn::ServerConfig>>, port: u16, bind_device: Option<&[u8]>) -> Result<Self, std::io::Error> {
+        let socket = udp::bind_std_socket(port, bind_device)?;
+        Ok(Self { socket, server_config, options: Default::default(), shutdown: Default::default() })
+    }
+
+    pub fn new_with_addr(server_config: Arc<RwLock<quinn::ServerConfig>>, addr: SocketAddr, bind_device: Option<&[u8]>) -> Result<Self, std::io::Error> {
+        let socket = udp::bind_std_socket_addr(addr, bind_device)?;
+        Ok(Self { socket, server_config, options: Default::default(), shutdown: Default::default() })
+    }
+
+    pub fn with_options(mut self, options: Arc<ServerOptions>) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// shutdown watched by `start`; `serve` takes its own
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// accept and serve connections on the current Tokio runtime until
+    /// `shutdown` is triggered and the open connections are drained
+    pub async fn serve(self, shutdown: Shutdown) -> Result<(), std::io::Error> {
+        let socket = self.socket;
+        let initial_config = self.server_config.read().clone();
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(initial_config),
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+        let local_addr = endpoint.local_addr()?;
+        let metrics = self.options.metrics.listener("quic", local_addr);
+        let slots = self.options.global_limiter.connection_slots();
+        let mut connections = JoinSet::new();
+
+        loop {
+            let incoming = tokio::select! {
+                incoming = endpoint.accept() => match incoming {
+                    Some(incoming) => incoming,
+                    None => break,
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = shutdown.triggered() => break,
+            };
+            let slot = match slots.try_acquire() {
+                Ok(slot) => slot,
+                Err(_) => {
+                    // answered with CONNECTION_REFUSED instead of queueing
+                    log::debug!("refusing quic connection from {}: connection limit reached", incoming.remote_address());
+                    incoming.refuse();
+                    continue;
+                }
+            };
+            let server_config = self.server_config.clone();
+            let options = self.options.clone();
+            let shutdown = shutdown.clone();
+            let metrics = metrics.clone();
+            connections.spawn(async move {
+                // picked per connection so that reloads apply without restarting the endpoint
+                let server_config = Arc::new(server_config.read().deref().clone());
+
+                let connecting = match incoming.accept_with(server_config) {
+                    Ok(connecting) => connecting,
+                    Err(err) => {
+                        log::error!("failed to accept quic connection: {err:#}");
+                        return;
+                    }
+                };
+                let _slot = slot;
+                let active = metrics.connection();
+                let conn = match connecting.await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        metrics.handshake_failures.add(1);
+                        log::error!("failed to perform quic handshake: {err:#}");
+                        return;
+                    }
+                };
+                let conn_info = Arc::new(ConnectionInfo {
+                    peer_addr: conn.remote_address(),
+                    tls: Some(TlsInfo::from_quic(&conn)),
+                });
+                let mut h3_conn: h3::server::Connection<_, Bytes> = match h3::server::Connection::new(h3_quinn::Connection::new(conn)).await {
+                    Ok(h3_conn) => h3_conn,
+                    Err(err) => {
+                        log::error!("failed to establish http3 connection: {err:#}");
+                        return;
+                    }
+                };
+
+                let _active = active;
+                let mut requests = JoinSet::new();
+                let mut draining = false;
+                loop {
+                    let accepted = tokio::select! {
+                        accepted = h3_conn.accept() => accepted,
+                        Some(_) = requests.join_next(), if !requests.is_empty() => {
+                            if draining && requests.is_empty() {
+                                break;
+                            }
+                            continue;
+                        }
+                        _ = shutdown.triggered(), if !draining => {
+                            // GOAWAY: no new requests, the ones in flight may finish
+                            draining = true;
+                            if let Err(err) = h3_conn.shutdown(0).await {
+                                log::debug!("failed to send http3 goaway: {err:#}");
+                                break;
+                            }
+                            if requests.is_empty() {
+                                break;
+                            }
+                            continue;
+                        }
+                        _ = shutdown.deadline(), if draining => break,
+                    };
+                    match accepted {
+                        Ok(Some(resolver)) => {
+                            let options = options.clone();
+                            let conn_info = conn_info.clone();
+                            requests.spawn(async move {
+                                let (req, stream) = match resolver.resolve_request().await {
+                                    Ok(resolved) => resolved,
+                                    Err(err) => {
+                                        log::error!("failed to resolve http3 request: {err:#}");
+                                        return;
+                                    }
+                                };
+                                if let Err(err) = serve_h3_request(req, stream, conn_info, options).await {
+                                    log::error!("failed to serve http3 request: {err:#}");
+                                }
+                            });
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            if !err.is_h3_no_error() {
+                                log::error!("http3 connection error: {err:#}");
+                            }
+                            break;
+                        }
+                    }
+                }
+                // requests left at the deadline are aborted, and dropping the
+                // connection sends CONNECTION_CLOSE with H3_NO_ERROR
+                requests.shutdown().await;
+            });
+        }
+
+        log::info!("quic listener {} closed, draining {} connections", local_addr, connections.len());
+        shutdown.drain(&mut connections).await;
+        // close whatever survived the drain and let the CONNECTION_CLOSE frames go out
+        endpoint.close(quinn::VarInt::from_u32(H3_NO_ERROR), b"server shutting down");
+        endpoint.wait_idle().await;
+        Ok(())
     }
 
-    /// start the server in background.
+    /// start the server in background, on a thread with its own runtime.
     pub fn start(self) -> std::thread::JoinHandle<()> {
         std::thread::spawn(move || {
             let rt = tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
                 .build()
                 .unwrap();
-            rt.block_on(self.run());
+            let shutdown = self.shutdown.clone();
+            if let Err(e) = rt.block_on(self.serve(shutdown)) {
+                log::error!("http3 server failed: {}", e);
+            }
+        })
+    }
+}
+
+/// admin listener serving only `/metrics`, kept off the public test ports
+pub struct MetricsServer {
+    listener: TcpListener,
+    metrics: Arc<ServerMetrics>,
+    shutdown: Shutdown,
+}
+
+impl MetricsServer {
+    pub fn new_with_addr(metrics: Arc<ServerMetrics>, addr: SocketAddr, bind_device: Option<&[u8]>) -> Result<Self, std::io::Error> {
+        let listener = tcp::listen_addr(addr, None, bind_device)?;
+        Ok(Self { listener, metrics, shutdown: Default::default() })
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// serve `/metrics` on the current Tokio runtime until `shutdown` is triggered
+    pub async fn serve(self, shutdown: Shutdown) -> Result<(), std::io::Error> {
+        self.listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(self.listener)?;
+        // scrapes in flight are aborted when this set is dropped
+        let mut connections = JoinSet::new();
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(_) => continue,
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = shutdown.triggered() => break,
+            };
+
+            let io = TokioIo::new(stream);
+            let metrics = self.metrics.clone();
+            connections.spawn(async move {
+                let service = service_fn(|req: Request<hyper::body::Incoming>| {
+                    let res = match (req.method(), req.uri().path()) {
+                        (&Method::GET, "/metrics") => metrics_response(&metrics),
+                        _ => json_response(StatusCode::NOT_FOUND, HttpVersion::Http1, serde_json::json!({
+                            "error": "not found"
+                        })),
+                    };
+                    futures::future::ready(Ok::<_, Infallible>(res))
+                });
+                if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
+                    log::debug!("metrics connection error: {:?}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// start the server in background, on a thread with its own runtime.
+    pub fn start(self) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let shutdown = self.shutdown.clone();
+            if let Err(e) = rt.block_on(self.serve(shutdown)) {
+                log::error!("metrics server failed: {}", e);
+            }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestResponse = Response<BoxBody<Bytes, Infallible>>;
+
+    async fn request(options: ServerOptions, method: Method, uri: &str, body: &'static [u8]) -> TestResponse {
+        let req = Request::builder().method(method).uri(uri).body(Full::new(Bytes::from_static(body))).unwrap();
+        let conn = Arc::new(ConnectionInfo { peer_addr: "192.0.2.1:40000".parse().unwrap(), tls: None });
+        handle_request(req, HttpVersion::Http2, conn, Arc::new(options)).await.unwrap()
+    }
+
+    async fn body_bytes(res: TestResponse) -> Bytes {
+        res.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    async fn body_json(res: TestResponse) -> serde_json::Value {
+        serde_json::from_slice(&body_bytes(res).await).unwrap()
+    }
+
+    fn header<'a>(res: &'a TestResponse, name: &str) -> Option<&'a str> {
+        res.headers().get(name).map(|v| v.to_str().unwrap())
+    }
+
+    /// data bytes and trailers of a streamed body
+    async fn drain(res: TestResponse) -> (u64, Option<header::HeaderMap>) {
+        let mut body = res.into_body();
+        let mut bytes = 0;
+        let mut trailers = None;
+        while let Some(frame) = body.frame().await {
+            match frame.unwrap().into_data() {
+                Ok(data) => bytes += data.len() as u64,
+                Err(frame) => trailers = frame.into_trailers().ok(),
+            }
+        }
+        (bytes, trailers)
+    }
+
+    #[tokio::test]
+    async fn ping_echoes_client_time_and_seq() {
+        let res = request(ServerOptions::default(), Method::GET, "/ping?t=1234.5&seq=7", b"").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "x-http-version"), Some("HTTP/2"));
+        assert_eq!(header(&res, "cache-control"), Some("no-store"));
+        let v = body_json(res).await;
+        assert_eq!(v["client_time_ms"], 1234.5);
+        assert_eq!(v["seq"], 7);
+        assert!(v["server_time_ms"].as_f64().unwrap() > 0.0);
+
+        let v = body_json(request(ServerOptions::default(), Method::GET, "/ping?t=soon", b"").await).await;
+        assert!(v.get("client_time_ms").is_none() && v.get("seq").is_none());
+
+        let res = request(ServerOptions::default(), Method::POST, "/ping", b"").await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn echo_returns_each_probe_stamped() {
+        let res = request(ServerOptions::default(), Method::POST, "/echo", b"{\"seq\":1}\n{\"seq\":2,\"tag\":\"x\"}\nnot json\n").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "x-http-version"), Some("HTTP/2"));
+        assert_eq!(header(&res, "content-type"), Some("application/x-ndjson"));
+        let body = body_bytes(res).await;
+        let lines: Vec<serde_json::Value> = body.split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["seq"], 1);
+        assert_eq!((&lines[1]["seq"], &lines[1]["tag"]), (&2.into(), &"x".into()));
+        assert_eq!(lines[2]["error"], "invalid probe");
+        for line in &lines {
+            let received = line["server_received_ms"].as_f64().unwrap();
+            assert!(line["server_sent_ms"].as_f64().unwrap() >= received);
+        }
+    }
+
+    #[tokio::test]
+    async fn echo_drops_overlong_probes() {
+        static LONG: [u8; ECHO_MAX_LINE + 1] = [b'x'; ECHO_MAX_LINE + 1];
+        let res = request(ServerOptions::default(), Method::POST, "/echo", &LONG).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(body_bytes(res).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn timed_download_accepts_duration_formats() {
+        for duration in ["20ms", "0.02s", "0.02"] {
+            let res = request(ServerOptions::default(), Method::GET, &format!("/download?duration={}", duration), b"").await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", duration);
+            drain(res).await;
+        }
+        for uri in ["/download", "/download?duration=soon", "/download?duration=-1s"] {
+            let res = request(ServerOptions::default(), Method::GET, uri, b"").await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+            assert_eq!(body_json(res).await["error"], "invalid duration");
+        }
+        let res = request(ServerOptions::default(), Method::GET, "/download?duration=61s", b"").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let v = body_json(res).await;
+        assert_eq!(v["error"], "duration too long");
+        assert_eq!(v["max_duration_ms"], 60_000);
+    }
+
+    #[tokio::test]
+    async fn timed_download_streams_until_deadline_then_sends_trailers() {
+        let options = ServerOptions::default();
+        let timed_downloads = options.timed_downloads.clone();
+        let start = Instant::now();
+        let res = request(options.clone(), Method::GET, "/download?duration=50ms&session=trailers", b"").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "trailer"), Some("x-transferred-bytes, x-elapsed-ms, x-samples"));
+        assert_eq!(header(&res, "x-http-version"), Some("HTTP/2"));
+        let (bytes, trailers) = drain(res).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(bytes > 0);
+        let trailers = trailers.expect("trailers after the body");
+        assert_eq!(trailers["x-transferred-bytes"].to_str().unwrap(), bytes.to_string());
+        let elapsed: f64 = trailers["x-elapsed-ms"].to_str().unwrap().parse().unwrap();
+        assert!(elapsed >= 50.0, "{}", elapsed);
+        assert!(serde_json::from_str::<serde_json::Value>(trailers["x-samples"].to_str().unwrap()).unwrap().is_array());
+
+        let status = timed_downloads.status_json("trailers").unwrap();
+        assert_eq!(status["active"], 0);
+        assert_eq!(status["transferred_bytes"], bytes);
+        assert_eq!(status["downloads"][0]["finished"], true);
+    }
+
+    #[tokio::test]
+    async fn download_status_reports_running_downloads() {
+        let options = ServerOptions::default();
+        let res = request(options.clone(), Method::GET, "/download/status", b"").await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = request(options.clone(), Method::GET, "/download/status?session=unknown", b"").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let download = request(options.clone(), Method::GET, "/download?duration=10s&session=running", b"").await;
+        let mut body = download.into_body();
+        let mut bytes = 0;
+        for _ in 0..4 {
+            bytes += body.frame().await.unwrap().unwrap().into_data().unwrap().len() as u64;
+        }
+        let res = request(options.clone(), Method::GET, "/download/status?session=running", b"").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "cache-control"), Some("no-store"));
+        let v = body_json(res).await;
+        assert_eq!(v["session"], "running");
+        assert_eq!(v["active"], 1);
+        // the last chunk handed out counts once the next one is asked for
+        assert_eq!(v["transferred_bytes"], bytes - ZEROS.len() as u64);
+        assert_eq!(v["downloads"][0]["finished"], false);
+
+        // a client going away finishes the download too
+        drop(body);
+        let v = body_json(request(options, Method::GET, "/download/status?session=running", b"").await).await;
+        assert_eq!(v["active"], 0);
+        assert_eq!(v["downloads"][0]["finished"], true);
+    }
+
+    #[test]
+    fn random_chunks_wrap_inside_the_block() {
+        let block = RandomBlock::default().bytes().unwrap();
+        assert_eq!(block.len(), RANDOM_BLOCK_SIZE);
+        assert!(block.iter().any(|b| *b != 0));
+        let source = PayloadSource::Random(block.clone());
+        let len = ZEROS.len();
+        // enough chunks to walk past the end of the block several times
+        for index in 0..4 * RANDOM_BLOCK_SIZE / len {
+            let chunk = source.chunk(index, len);
+            assert_eq!(chunk.len(), len);
+            let start = index * (len + 4099) % (RANDOM_BLOCK_SIZE - len + 1);
+            assert_eq!(chunk, block.slice(start..start + len), "chunk {}", index);
+        }
+        // neighbouring chunks do not repeat each other
+        assert_ne!(source.chunk(0, len), source.chunk(1, len));
+        // short final chunks of `/download/N` stay in bounds too
+        assert_eq!(source.chunk(usize::MAX, 1000).len(), 1000);
+        assert_eq!(PayloadSource::Zeros.chunk(3, 1000), Bytes::from_static(&ZEROS[..1000]));
+    }
+
+    #[test]
+    fn random_block_is_generated_once() {
+        let block = RandomBlock::default();
+        let shared = block.clone();
+        assert_eq!(format!("{:?}", shared), "RandomBlock(not generated)");
+        let bytes = block.bytes().unwrap();
+        assert_eq!(shared.bytes().unwrap().as_ptr(), bytes.as_ptr());
+        assert_ne!(RandomBlock::default().bytes().unwrap(), bytes);
+    }
+
+    #[tokio::test]
+    async fn payload_parameter_selects_content() {
+        let random = body_bytes(request(ServerOptions::default(), Method::GET, "/download/200000?payload=random", b"").await).await;
+        assert_eq!(random.len(), 200_000);
+        // all but certain to have far fewer zero bytes than this
+        assert!(random.iter().filter(|b| **b == 0).count() < 2000);
+
+        let zeros = body_bytes(request(ServerOptions::default(), Method::GET, "/download/200000?payload=zeros", b"").await).await;
+        assert_eq!(zeros.len(), 200_000);
+        assert!(zeros.iter().all(|b| *b == 0));
+
+        // the default applies without a parameter
+        let options = ServerOptions::default().with_default_payload(Payload::Random);
+        let default = body_bytes(request(options, Method::GET, "/download/65536", b"").await).await;
+        assert!(default.iter().any(|b| *b != 0));
+
+        for uri in ["/download/1000?payload=noise", "/download?duration=10ms&payload=noise"] {
+            let res = request(ServerOptions::default(), Method::GET, uri, b"").await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", uri);
+            assert_eq!(body_json(res).await["error"], "invalid payload");
+        }
+    }
+
+    #[tokio::test]
+    async fn session_ids_are_handed_out_and_echoed() {
+        let res = request(ServerOptions::default(), Method::POST, "/session", b"").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let id = body_json(res).await["session_id"].as_str().unwrap().to_owned();
+        assert_eq!(id.len(), 32);
+        assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+
+        let res = request(ServerOptions::default(), Method::GET, &format!("/ping?session={}", id), b"").await;
+        assert_eq!(header(&res, "x-test-session"), Some(id.as_str()));
+        let res = request(ServerOptions::default(), Method::GET, "/ping?session=not%20valid", b"").await;
+        assert_eq!(header(&res, "x-test-session"), None);
+    }
+
+    #[tokio::test]
+    async fn timed_download_ends_at_test_duration_cap() {
+        let options = ServerOptions::default().with_max_test_duration(Duration::from_millis(50));
+        let start = Instant::now();
+        let res = request(options, Method::GET, "/download?duration=10s", b"").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let (bytes, trailers) = drain(res).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        assert_eq!(trailers.unwrap()["x-transferred-bytes"].to_str().unwrap(), bytes.to_string());
+    }
+}
@@ -0,0 +1,160 @@
+
+use crate::deps;
+
+use deps::tokio;
+use deps::log;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// stops a set of servers: once triggered, listeners close, open connections
+/// are asked to finish and whatever is still running at the drain deadline is cut
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    /// drain deadline, `None` until triggered
+    deadline: Arc<watch::Sender<Option<Instant>>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (deadline, _) = watch::channel(None);
+        Self { deadline: Arc::new(deadline) }
+    }
+
+    /// start shutting down, giving open connections `drain` to finish;
+    /// later calls keep the first deadline
+    pub fn trigger(&self, drain: Duration) {
+        self.deadline.send_if_modified(|deadline| {
+            if deadline.is_some() {
+                return false;
+            }
+            *deadline = Some(Instant::now() + drain);
+            true
+        });
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.deadline.borrow().is_some()
+    }
+
+    async fn wait_deadline(&self) -> Instant {
+        let mut rx = self.deadline.subscribe();
+        let deadline = match rx.wait_for(Option::is_some).await {
+            Ok(deadline) => *deadline,
+            // unreachable while `self` holds the sender
+            Err(_) => None,
+        };
+        match deadline {
+            Some(deadline) => deadline,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// resolves once shutdown is triggered
+    pub async fn triggered(&self) {
+        self.wait_deadline().await;
+    }
+
+    /// resolves when the drain period is over
+    pub async fn deadline(&self) {
+        let deadline = self.wait_deadline().await;
+        tokio::time::sleep_until(deadline).await;
+    }
+
+    /// wait for `tasks` to finish, aborting those left at the drain deadline
+    pub async fn drain<T: 'static>(&self, tasks: &mut JoinSet<T>) {
+        let deadline = self.deadline();
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                next = tasks.join_next() => if next.is_none() {
+                    return;
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        log::info!("aborting {} tasks at the drain deadline", tasks.len());
+        tasks.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn second_trigger_keeps_first_deadline() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_triggered());
+        let start = Instant::now();
+        shutdown.trigger(Duration::from_secs(10));
+        assert!(shutdown.is_triggered());
+        tokio::time::advance(Duration::from_secs(5)).await;
+        shutdown.trigger(Duration::from_secs(1));
+        shutdown.deadline().await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(10) && elapsed < Duration::from_millis(10_010), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn triggered_waits_for_trigger() {
+        let shutdown = Shutdown::new();
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.triggered().await }
+        });
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(!waiter.is_finished());
+        shutdown.trigger(Duration::from_secs(1));
+        waiter.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_returns_once_tasks_finish() {
+        let shutdown = Shutdown::new();
+        let mut tasks = JoinSet::new();
+        for secs in 1..=3 {
+            tasks.spawn(tokio::time::sleep(Duration::from_secs(secs)));
+        }
+        let start = Instant::now();
+        shutdown.trigger(Duration::from_secs(10));
+        shutdown.drain(&mut tasks).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(3) && elapsed < Duration::from_secs(4), "{:?}", elapsed);
+        assert!(tasks.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_aborts_tasks_at_deadline() {
+        let shutdown = Shutdown::new();
+        let finished = Arc::new(AtomicBool::new(false));
+        let mut tasks = JoinSet::new();
+        tasks.spawn(tokio::time::sleep(Duration::from_secs(1)));
+        tasks.spawn({
+            let finished = finished.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                finished.store(true, Ordering::SeqCst);
+            }
+        });
+        let start = Instant::now();
+        shutdown.trigger(Duration::from_secs(5));
+        shutdown.drain(&mut tasks).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(5) && elapsed < Duration::from_secs(6), "{:?}", elapsed);
+        assert!(tasks.is_empty());
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        assert!(!finished.load(Ordering::SeqCst));
+    }
+}
@@ -1,5 +1,10 @@
 
 use crate::deps;
+use crate::dns::HostAddrs;
+use crate::inet::{
+    AddressFamily,
+    CONNECTION_ATTEMPT_DELAY,
+};
 
 use std::net::SocketAddr;
 use std::net::{
@@ -9,6 +14,7 @@ use std::net::{
 
 use deps::socket2;
 use deps::tokio;
+use deps::futures::stream::{FuturesUnordered, StreamExt};
 
 use socket2::{
     Socket,
@@ -16,17 +22,38 @@ use socket2::{
     Type,
 };
 
-use std::io::Error;
+use std::io::{
+    Error,
+    ErrorKind,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
 
 pub const DEFAULT_BACKLOG: i32 = 1024;
 
 /// bind with port 0 to get an available port for client connections
 pub fn listen(port: u16, backlog: Option<i32>, device: Option<&[u8]>) -> Result<TcpListener, Error> {
     let socket_addr = crate::inet::socket_addr_unspecified(port);
+    listen_addr(socket_addr, backlog, device)
+}
+
+/// listen on a specific address; `[::]` accepts IPv4 as well
+pub fn listen_addr(socket_addr: SocketAddr, backlog: Option<i32>, device: Option<&[u8]>) -> Result<TcpListener, Error> {
     let backlog = backlog.unwrap_or(DEFAULT_BACKLOG);
 
-    let socket = Socket::new(Domain::IPV6, Type::STREAM, None)?;
-    socket.set_only_v6(false)?;
+    let socket = match &socket_addr {
+        SocketAddr::V4(_) => Socket::new(Domain::IPV4, Type::STREAM, None)?,
+        SocketAddr::V6(_) => {
+            let socket = Socket::new(Domain::IPV6, Type::STREAM, None)?;
+            socket.set_only_v6(false)?;
+            socket
+        }
+    };
     socket.set_reuse_address(true)?;
     socket.set_nonblocking(true)?;
 
@@ -42,7 +69,7 @@ pub fn listen(port: u16, backlog: Option<i32>, device: Option<&[u8]>) -> Result<
 
     socket.bind(&socket_addr.into())?;
 
-    socket.listen(backlog.into())?;
+    socket.listen(backlog)?;
     let listener: TcpListener = socket.into();
 
     Ok(listener)
@@ -53,6 +80,7 @@ pub async fn connect(addr: SocketAddr, device: Option<&[u8]>) -> Result<tokio::n
         SocketAddr::V4(_) => Socket::new(Domain::IPV4, Type::STREAM, None)?,
         SocketAddr::V6(_) => Socket::new(Domain::IPV6, Type::STREAM, None)?,
     };
+    socket.set_nonblocking(true)?;
 
     #[cfg(target_os = "linux")]
     if device.is_some() {
@@ -70,3 +98,259 @@ pub async fn connect(addr: SocketAddr, device: Option<&[u8]>) -> Result<tokio::n
     let stream = socket.connect(addr).await?;
     Ok(stream)
 }
+
+async fn connect_attempt(addr: SocketAddr, device: Option<&[u8]>) -> (SocketAddr, Result<tokio::net::TcpStream, Error>) {
+    (addr, connect(addr, device).await)
+}
+
+/// race the addresses of a host as browsers do (RFC 8305): IPv6 first, alternating
+/// families, a new attempt every `CONNECTION_ATTEMPT_DELAY` or as soon as one fails;
+/// takes the host already resolved rather than its name, since the client looks
+/// it up once through its `DnsResolver` pool and HTTP/3 connects to the same addresses
+pub async fn connect_happy_eyeballs(host: &HostAddrs, port: u16, device: Option<&[u8]>) -> Result<(tokio::net::TcpStream, AddressFamily), Error> {
+    let mut pending = host.interleaved(port).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(connect_attempt(addr, device)),
+                None => {
+                    return Err(last_error.unwrap_or_else(|| Error::new(ErrorKind::AddrNotAvailable, "no addresses found")));
+                }
+            }
+        }
+
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => {
+                match result {
+                    Ok(stream) => return Ok((stream, AddressFamily::of(&addr))),
+                    Err(e) => {
+                        log::debug!("connection attempt to {} failed: {}", addr, e);
+                        last_error = Some(e);
+                        if let Some(addr) = pending.next() {
+                            attempts.push(connect_attempt(addr, device));
+                        }
+                    }
+                }
+            }
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(connect_attempt(addr, device));
+                }
+            }
+        }
+    }
+}
+
+/// fails reads and writes with `TimedOut` once the stream has seen no
+/// progress in either direction for `timeout`
+pub struct IdleTimeout<T> {
+    inner: T,
+    /// `None` passes everything through
+    timeout: Option<Duration>,
+    last_activity: Instant,
+    /// reads and writes keep timers of their own: the halves of a split
+    /// stream are polled from different tasks and each needs to be woken
+    read_timer: Option<Pin<Box<Sleep>>>,
+    write_timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> IdleTimeout<T> {
+    pub fn new(inner: T, timeout: Option<Duration>) -> Self {
+        Self { inner, timeout, last_activity: Instant::now(), read_timer: None, write_timer: None }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// called when the inner stream is pending; ready with an error once idle for too long
+fn poll_idle(timer: &mut Option<Pin<Box<Sleep>>>, timeout: Option<Duration>, last_activity: Instant, cx: &mut Context<'_>) -> Poll<Error> {
+    let deadline = match timeout {
+        Some(timeout) => last_activity + timeout,
+        None => return Poll::Pending,
+    };
+    let sleep = timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+    if sleep.deadline() != deadline {
+        sleep.as_mut().reset(deadline);
+    }
+    sleep.as_mut().poll(cx).map(|()| Error::new(ErrorKind::TimedOut, "connection idle for too long"))
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.last_activity = Instant::now();
+                Poll::Ready(result)
+            }
+            Poll::Pending => poll_idle(&mut this.read_timer, this.timeout, this.last_activity, cx).map(Err),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                this.last_activity = Instant::now();
+                Poll::Ready(result)
+            }
+            Poll::Pending => poll_idle(&mut this.write_timer, this.timeout, this.last_activity, cx).map(Err),
+        }
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[std::io::IoSlice<'_>]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write_vectored(cx, bufs) {
+            Poll::Ready(result) => {
+                this.last_activity = Instant::now();
+                Poll::Ready(result)
+            }
+            Poll::Pending => poll_idle(&mut this.write_timer, this.timeout, this.last_activity, cx).map(Err),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_flush(cx) {
+            Poll::Ready(result) => Poll::Ready(result),
+            Poll::Pending => poll_idle(&mut this.write_timer, this.timeout, this.last_activity, cx).map(Err),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn both_loopbacks() -> HostAddrs {
+        HostAddrs { addrs_v4: vec![Ipv4Addr::LOCALHOST], addrs_v6: vec![Ipv6Addr::LOCALHOST] }
+    }
+
+    fn listen_loopback(ip: IpAddr, port: u16, backlog: i32) -> TcpListener {
+        listen_addr(SocketAddr::new(ip, port), Some(backlog), None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_prefers_ipv6() {
+        let v4 = listen_loopback(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, 16);
+        let port = v4.local_addr().unwrap().port();
+        let _v6 = listen_loopback(IpAddr::V6(Ipv6Addr::LOCALHOST), port, 16);
+        let (stream, family) = connect_happy_eyeballs(&both_loopbacks(), port, None).await.unwrap();
+        assert_eq!(family, AddressFamily::V6);
+        assert!(stream.peer_addr().unwrap().is_ipv6());
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_fails_over_at_once_when_refused() {
+        // nothing listens on [::1]:port, so the first attempt is refused
+        let v4 = listen_loopback(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, 16);
+        let port = v4.local_addr().unwrap().port();
+        let start = std::time::Instant::now();
+        let (_stream, family) = connect_happy_eyeballs(&both_loopbacks(), port, None).await.unwrap();
+        assert_eq!(family, AddressFamily::V4);
+        assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY, "{:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_staggers_next_attempt() {
+        let v4 = listen_loopback(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, 16);
+        let port = v4.local_addr().unwrap().port();
+        // with its accept queue full, the IPv6 listener drops further SYNs and
+        // the attempt hangs instead of failing
+        let v6 = listen_loopback(IpAddr::V6(Ipv6Addr::LOCALHOST), port, 0);
+        let _queued = connect(v6.local_addr().unwrap(), None).await.unwrap();
+        let start = std::time::Instant::now();
+        let (_stream, family) = connect_happy_eyeballs(&both_loopbacks(), port, None).await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(family, AddressFamily::V4);
+        assert!(elapsed >= CONNECTION_ATTEMPT_DELAY && elapsed < CONNECTION_ATTEMPT_DELAY * 2, "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_reports_last_error() {
+        let v4 = listen_loopback(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, 16);
+        let port = v4.local_addr().unwrap().port();
+        drop(v4);
+        let err = connect_happy_eyeballs(&both_loopbacks(), port, None).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+
+        let none = HostAddrs { addrs_v4: Vec::new(), addrs_v6: Vec::new() };
+        let err = connect_happy_eyeballs(&none, port, None).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrNotAvailable);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_read_times_out() {
+        let (stream, _peer) = tokio::io::duplex(64);
+        let mut stream = IdleTimeout::new(stream, Some(TIMEOUT));
+        let start = Instant::now();
+        let err = stream.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn activity_postpones_timeout() {
+        let (stream, mut peer) = tokio::io::duplex(64);
+        let mut stream = IdleTimeout::new(stream, Some(TIMEOUT));
+        let start = Instant::now();
+        let writer = tokio::spawn(async move {
+            for _ in 0..4 {
+                tokio::time::sleep(TIMEOUT / 2).await;
+                peer.write_all(b"x").await.unwrap();
+            }
+            peer
+        });
+        let mut buf = [0; 16];
+        for _ in 0..4 {
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 1);
+        }
+        let _peer = writer.await.unwrap();
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), TIMEOUT * 2 + TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn split_halves_both_time_out() {
+        let (stream, _peer) = tokio::io::duplex(64);
+        let (mut read, mut write) = tokio::io::split(IdleTimeout::new(stream, Some(TIMEOUT)));
+        let reader = tokio::spawn(async move { read.read(&mut [0; 16]).await });
+        // more than the duplex buffer holds, so the write stays pending
+        let writer = tokio::spawn(async move { write.write_all(&[0; 1024]).await });
+        let (read, write) = tokio::time::timeout(TIMEOUT * 2, async { (reader.await.unwrap(), writer.await.unwrap()) })
+            .await
+            .expect("both halves should time out");
+        assert_eq!(read.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(write.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_timeout_passes_through() {
+        let (stream, _peer) = tokio::io::duplex(64);
+        let mut stream = IdleTimeout::new(stream, None);
+        let read = tokio::time::timeout(TIMEOUT * 10, stream.read(&mut [0; 16])).await;
+        assert!(read.is_err());
+    }
+}
@@ -0,0 +1,36 @@
+
+/// UTC calendar date and time of day, `(year, month, day, hour, minute, second)`
+pub type CivilTime = (i64, u32, u32, u32, u32, u32);
+
+/// civil date and time `secs` after the unix epoch, after Howard Hinnant's
+/// days-from-civil algorithm
+pub fn civil_from_unix(secs: u64) -> CivilTime {
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month as u32, day as u32, (rem / 3600) as u32, (rem / 60 % 60) as u32, (rem % 60) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_unix(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil_from_unix(951_782_399), (2000, 2, 28, 23, 59, 59));
+        assert_eq!(civil_from_unix(951_827_696), (2000, 2, 29, 12, 34, 56));
+        assert_eq!(civil_from_unix(951_868_800), (2000, 3, 1, 0, 0, 0));
+        assert_eq!(civil_from_unix(2_524_607_999), (2049, 12, 31, 23, 59, 59));
+        assert_eq!(civil_from_unix(4_107_542_400), (2100, 3, 1, 0, 0, 0));
+    }
+}
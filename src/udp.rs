@@ -6,20 +6,33 @@ use deps::tokio::net::UdpSocket;
 
 use deps::net2::UdpBuilder;
 
+use std::net::SocketAddr;
 use std::io::Error;
 
 /// bind with port 0 to get an available port for client connections
 pub fn bind_socket(port: u16, device: Option<&[u8]>) -> Result<UdpSocket, Error> {
+    UdpSocket::from_std(bind_std_socket(port, device)?)
+}
+
+/// like `bind_socket`, but a non-blocking std socket, as quinn takes them
+pub fn bind_std_socket(port: u16, device: Option<&[u8]>) -> Result<std::net::UdpSocket, Error> {
     let socket_addr = inet::socket_addr_unspecified(port);
-    let socket = UdpBuilder::new_v6()?
-        .only_v6(false)?
-        .bind(socket_addr)?;
+    bind_std_socket_addr(socket_addr, device)
+}
+
+/// bind to a specific address; `[::]` accepts IPv4 as well
+pub fn bind_std_socket_addr(socket_addr: SocketAddr, device: Option<&[u8]>) -> Result<std::net::UdpSocket, Error> {
+    let socket = match &socket_addr {
+        SocketAddr::V4(_) => UdpBuilder::new_v4()?.bind(socket_addr)?,
+        SocketAddr::V6(_) => UdpBuilder::new_v6()?
+            .only_v6(false)?
+            .bind(socket_addr)?,
+    };
     socket.set_nonblocking(true)?;
-    let socket = UdpSocket::from_std(socket)?;
 
     #[cfg(target_os = "linux")]
     if device.is_some() {
-        socket.bind_device(device)?;
+        deps::socket2::SockRef::from(&socket).bind_device(device)?;
     }
 
     #[cfg(not(target_os = "linux"))]